@@ -1,61 +1,774 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs;
-use rayon::iter::{IntoParallelRefIterator, ParallelBridge, ParallelIterator};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use bincode::Options;
 use serde::{Serialize, Deserialize};
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+use crate::error::{Error, IoContext, Result};
+use crate::index::IndexEntry;
+use crate::metadata::Metadata;
+use crate::pattern::Selection;
+use crate::stream::FrameReader;
+
+/// An archive held completely in memory. Its serialized form is the entry stream.
+#[derive(Debug, PartialEq, Eq)]
 pub enum Archive {
     Directory{
         name: String,
+        metadata: Metadata,
         children: Vec<Archive>
     },
     File{
         name: String,
+        metadata: Metadata,
+        /// CRC32C of the content.
+        checksum: u32,
         content: Vec<u8>,
+    },
+    /// A symbolic link, the target is stored verbatim and not resolved.
+    Symlink{
+        name: String,
+        metadata: Metadata,
+        target: String,
+    },
+    /// A hard link to a file stored elsewhere in the archive.
+    /// The target is the path of that file relative to the extraction directory, including the root name.
+    Hardlink{
+        name: String,
+        target: String,
+    },
+}
+
+/// A record of the entry stream, the format everything is compressed in.
+///
+/// Entries are written depth first, every directory before its children. A `File` entry is directly
+/// followed by `size` bytes of content and the CRC32C of the content as little endian `u32`.
+/// Paths are separated by `/` and relative to the extraction directory, so they start with the root name.
+/// The stream ends with a `None` record.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Entry {
+    Directory{
+        path: String,
+        metadata: Metadata,
+    },
+    File{
+        path: String,
+        metadata: Metadata,
+        size: u64,
+    },
+    Symlink{
+        path: String,
+        metadata: Metadata,
+        target: String,
+    },
+    Hardlink{
+        path: String,
+        target: String,
+    },
+}
+
+impl Entry {
+    pub fn path(&self) -> &str {
+        match self {
+            Entry::Directory { path, .. }
+            | Entry::File { path, .. }
+            | Entry::Symlink { path, .. }
+            | Entry::Hardlink { path, .. } => path,
+        }
     }
 }
 
+/// What to do when an extracted entry already exists on disk.
+/// Existing directories are always merged with the extracted ones.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnConflict {
+    /// Keep the existing entry
+    Skip,
+    /// Replace the existing entry
+    Overwrite,
+    /// Replace the existing entry if the extracted one was modified later
+    Newer,
+    /// Extract under the first free name with a numeric suffix, `name.1`, `name.2`, ...
+    Rename,
+    /// Stop with an error
+    Abort,
+}
+
+/// Settings of `Archive::stream_to_disk`.
+#[derive(Debug, Clone, Copy)]
+pub struct ExtractOptions {
+    pub on_conflict: OnConflict,
+    /// Skips the path checks, only for trusted archives.
+    pub allow_unsafe: bool,
+}
+
+/// An entry an extraction did not simply create or replace, in the order they happened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExtractEvent {
+    /// The entry would replace an existing directory, it was skipped instead.
+    DirectoryInTheWay(String),
+    /// The entry was extracted to another path because something exists at its own.
+    Renamed { path: String, to: String },
+    /// A hard link was skipped, its target was not extracted.
+    MissingLinkTarget { path: String, target: String },
+}
+
+impl fmt::Display for ExtractEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExtractEvent::DirectoryInTheWay(path) => write!(f, "Skipping {}, a directory is in the way", path),
+            ExtractEvent::Renamed { path, to } => write!(f, "Extracting {} as {}", path, to),
+            ExtractEvent::MissingLinkTarget { path, target } => write!(f, "Skipping hard link {}, its target {} was not extracted", path, target),
+        }
+    }
+}
+
+/// Counts of what an extraction did and the entries that need a mention, printed when it is finished.
+#[derive(Debug, Default)]
+pub struct ExtractSummary {
+    pub created: usize,
+    pub overwritten: usize,
+    pub renamed: usize,
+    pub skipped: usize,
+    pub merged: usize,
+    pub events: Vec<ExtractEvent>,
+}
+
+impl fmt::Display for ExtractSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} created, {} overwritten, {} renamed, {} skipped, {} directories merged",
+            self.created, self.overwritten, self.renamed, self.skipped, self.merged)
+    }
+}
+
+/// What writing an archive did.
+#[derive(Debug, Default)]
+pub struct ArchiveSummary {
+    /// Size of the entry stream.
+    pub size: u64,
+    /// Disk paths of the FIFOs, sockets and devices that were not archived.
+    pub skipped: Vec<String>,
+}
+
+/// Upper bound for a single entry record, protects against huge allocations from corrupt lengths.
+const MAX_ENTRY_SIZE: u64 = 1 << 20;
+
+fn entry_options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(MAX_ENTRY_SIZE)
+}
+
+pub fn write_entry(output: &mut impl Write, entry: Option<&Entry>) -> Result<()> {
+    entry_options().serialize_into(output, &entry).map_err(|error| match *error {
+        bincode::ErrorKind::SizeLimit => Error::InvalidName(entry.map(Entry::path).unwrap_or_default().to_string()),
+        _ => Error::from(error),
+    })
+}
+
+/// Writes a file content record, the content followed by its checksum.
+fn write_content(output: &mut impl Write, content: &[u8], checksum: u32) -> Result<()> {
+    output.write_all(content).map_err(|e| Error::from_stream(e, "archive"))?;
+    output.write_all(&checksum.to_le_bytes()).map_err(|e| Error::from_stream(e, "archive"))
+}
+
+/// Writes the records of an entry stream and collects the index entries pointing to them.
+pub struct EntryWriter<W: Write> {
+    output: W,
+    /// Number of bytes written so far.
+    offset: u64,
+    index: Vec<IndexEntry>,
+}
+
+impl<W: Write> EntryWriter<W> {
+    pub fn new(output: W) -> Self {
+        EntryWriter { output, offset: 0, index: Vec::new() }
+    }
+
+    pub fn write_entry(&mut self, entry: Entry) -> Result<()> {
+        let mut record = Vec::new();
+        write_entry(&mut record, Some(&entry))?;
+        self.write_content(&record)?;
+        self.index.push(IndexEntry { entry, content_offset: self.offset });
+        Ok(())
+    }
+
+    pub fn write_content(&mut self, content: &[u8]) -> Result<()> {
+        self.output.write_all(content).map_err(|e| Error::from_stream(e, "archive"))?;
+        self.offset += content.len() as u64;
+        Ok(())
+    }
+
+    /// Writes the end record and returns the index entries of everything written.
+    pub fn finish(mut self) -> Result<Vec<IndexEntry>> {
+        write_entry(&mut self.output, None)?;
+        Ok(self.index)
+    }
+}
+
+/// Reads the records of an entry stream.
+pub struct EntryReader<R: Read> {
+    input: R,
+}
+
+impl<R: Read> EntryReader<R> {
+    pub fn new(input: R) -> Self {
+        EntryReader { input }
+    }
+
+    pub fn next_entry(&mut self) -> Result<Option<Entry>> {
+        Ok(entry_options().deserialize_from(&mut self.input)?)
+    }
+
+    /// Copies the content of the current `File` entry into `output`.
+    /// Returns whether the content matches its checksum.
+    pub fn copy_content(&mut self, size: u64, output: &mut impl Write, path: &str) -> Result<bool> {
+        let (stored, computed) = self.copy_checked(size, output, path)?;
+        Ok(stored == computed)
+    }
+
+    pub fn skip_content(&mut self, size: u64) -> Result<bool> {
+        self.copy_content(size, &mut io::sink(), "")
+    }
+
+    /// Iterates over the entries, skipping file contents.
+    pub fn entries(mut self) -> impl Iterator<Item = Result<Entry>> {
+        std::iter::from_fn(move || match self.next_entry() {
+            Ok(Some(entry)) => match entry {
+                Entry::File { size, .. } => Some(self.skip_content(size).map(|_| entry)),
+                entry => Some(Ok(entry)),
+            },
+            Ok(None) => None,
+            Err(error) => Some(Err(error)),
+        })
+    }
+
+    /// Reads the content of the current `File` entry together with its stored checksum.
+    pub fn read_content(&mut self, size: u64) -> Result<(Vec<u8>, u32)> {
+        let mut content = Vec::new();
+        let (stored, _) = self.copy_checked(size, &mut content, "")?;
+        Ok((content, stored))
+    }
+
+    /// Returns the stored and the computed checksum of the copied content.
+    fn copy_checked(&mut self, size: u64, output: &mut impl Write, path: &str) -> Result<(u32, u32)> {
+        let mut checksum = 0;
+        let mut buffer = vec![0; 1 << 16];
+        let mut remaining = size;
+        while remaining > 0 {
+            let len = remaining.min(buffer.len() as u64) as usize;
+            self.input.read_exact(&mut buffer[..len]).map_err(|e| Error::from_stream(e, "archive"))?;
+            checksum = crc32c::crc32c_append(checksum, &buffer[..len]);
+            output.write_all(&buffer[..len]).with_path(path)?;
+            remaining -= len as u64;
+        }
+        let mut stored = [0; 4];
+        self.input.read_exact(&mut stored).map_err(|e| Error::from_stream(e, "archive"))?;
+        Ok((u32::from_le_bytes(stored), checksum))
+    }
+}
+
+/// Device and inode of a file, used to detect hard links and directory loops.
+type FileId = (u64, u64);
+
+#[cfg(unix)]
+fn file_id(metadata: &fs::Metadata) -> Option<FileId> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_metadata: &fs::Metadata) -> Option<FileId> {
+    None
+}
+
+#[cfg(unix)]
+fn link_count(metadata: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.nlink()
+}
+
+#[cfg(not(unix))]
+fn link_count(_metadata: &fs::Metadata) -> u64 {
+    1
+}
+
+#[cfg(unix)]
+fn create_symlink(target: &str, path: &str) -> Result<()> {
+    std::os::unix::fs::symlink(target, path).with_path(path)
+}
+
+#[cfg(windows)]
+fn create_symlink(target: &str, path: &str) -> Result<()> {
+    let is_dir = Path::new(path).parent().map(|parent| parent.join(target).is_dir()).unwrap_or(false);
+    if is_dir {
+        std::os::windows::fs::symlink_dir(target, path).with_path(path)
+    } else {
+        std::os::windows::fs::symlink_file(target, path).with_path(path)
+    }
+}
+
+/// Checks that an archive path stays inside the extraction directory: it has to be relative
+/// and must not contain `.` or `..` components, NUL bytes or platform path separators.
+pub fn validate_path(path: &str) -> Result<()> {
+    let is_unsafe = |component: &str| component.is_empty()
+        || component == "."
+        || component == ".."
+        || component.contains(|c: char| c == '\0' || std::path::is_separator(c))
+        || (cfg!(windows) && component.contains(':'));
+    match path.split('/').any(is_unsafe) {
+        true => Err(Error::UnsafePath { path: path.to_string(), reason: "it is absolute or contains unsafe components" }),
+        false => Ok(()),
+    }
+}
+
+/// FIFOs, sockets and devices, which have no entry type.
+fn is_special(metadata: &fs::Metadata) -> bool {
+    let file_type = metadata.file_type();
+    !(file_type.is_file() || file_type.is_dir() || file_type.is_symlink())
+}
+
+fn utf8_name(name: &std::ffi::OsStr) -> Result<&str> {
+    name.to_str().ok_or_else(|| Error::InvalidName(name.to_string_lossy().into_owned()))
+}
+
 impl Archive {
     pub fn serialize(&self) -> Vec<u8> {
-        bincode::serialize(&self).unwrap()
+        let mut output = Vec::new();
+        self.serialize_into(&mut output, "").expect("writing into memory can not fail");
+        write_entry(&mut output, None).expect("writing into memory can not fail");
+        output
     }
 
-    pub fn deserialize(data: &[u8]) -> Archive {
-        bincode::deserialize(data).unwrap()
+    fn serialize_into(&self, output: &mut Vec<u8>, parent: &str) -> Result<()> {
+        let path = parent.to_string() + self.name();
+        match self {
+            Archive::Directory { metadata, children, .. } => {
+                write_entry(output, Some(&Entry::Directory { path: path.clone(), metadata: metadata.clone() }))?;
+                children.iter().try_for_each(|child| child.serialize_into(output, &(path.clone() + "/")))
+            },
+            Archive::File { metadata, checksum, content, .. } => {
+                write_entry(output, Some(&Entry::File { path, metadata: metadata.clone(), size: content.len() as u64 }))?;
+                write_content(output, content, *checksum)
+            },
+            Archive::Symlink { metadata, target, .. } => {
+                write_entry(output, Some(&Entry::Symlink { path, metadata: metadata.clone(), target: target.clone() }))
+            },
+            Archive::Hardlink { target, .. } => {
+                write_entry(output, Some(&Entry::Hardlink { path, target: target.clone() }))
+            },
+        }
     }
 
-    pub fn read_from_disk(path: &str) -> Archive {
-        let full_path = fs::canonicalize(path).unwrap();
-        let dir_name = full_path.file_name().unwrap().to_str().unwrap();
-        if full_path.is_file() {
-            return Self::File {
-                name: dir_name.to_string(),
-                content: fs::read(path).unwrap(),
+    /// Rebuilds the tree from an entry stream.
+    pub fn deserialize(data: &[u8]) -> Result<Archive> {
+        let mut reader = EntryReader::new(data);
+        // Directories that are still open, paired with their path.
+        let mut open: Vec<(String, Archive)> = Vec::new();
+        let mut root = None;
+        while let Some(entry) = reader.next_entry()? {
+            validate_path(entry.path())?;
+            if let Entry::Hardlink { target, .. } = &entry {
+                validate_path(target)?;
+            }
+            let path = entry.path().to_string();
+            let name = path.rsplit('/').next().unwrap_or_default().to_string();
+            let archive = match entry {
+                Entry::Directory { metadata, .. } => Archive::Directory { name, metadata, children: Vec::new() },
+                Entry::File { metadata, size, .. } => {
+                    let (content, checksum) = reader.read_content(size)?;
+                    Archive::File { name, metadata, checksum, content }
+                },
+                Entry::Symlink { metadata, target, .. } => Archive::Symlink { name, metadata, target },
+                Entry::Hardlink { target, .. } => Archive::Hardlink { name, target },
+            };
+            while open.last().is_some_and(|(dir, _)| !path.starts_with(&(dir.clone() + "/"))) {
+                let (_, dir) = open.pop().unwrap();
+                Self::attach(&mut open, &mut root, dir)?;
+            }
+            if open.is_empty() && root.is_some() {
+                return Err(Error::corrupt(format!("{} is outside of the archive root", path)));
+            }
+            match archive {
+                Archive::Directory { .. } => open.push((path, archive)),
+                archive => Self::attach(&mut open, &mut root, archive)?,
             }
         }
+        while let Some((_, dir)) = open.pop() {
+            Self::attach(&mut open, &mut root, dir)?;
+        }
+        root.ok_or_else(|| Error::corrupt("archive contains no entries"))
+    }
 
-        let children = fs::read_dir(path).unwrap()
-            .map(|entry| entry.unwrap().path().file_name().unwrap().to_str().unwrap().to_string())
-            .par_bridge()
-            .map(|child_path| Self::read_from_disk(&(path.to_string() + "/" + &child_path)))
-            .collect::<Vec<_>>();
-
-        Self::Directory {
-            name: dir_name.to_string(),
-            children,
+    /// Adds a finished entry to the innermost open directory or makes it the root.
+    fn attach(open: &mut [(String, Archive)], root: &mut Option<Archive>, archive: Archive) -> Result<()> {
+        match open.last_mut() {
+            Some((_, Archive::Directory { children, .. })) => children.push(archive),
+            _ if root.is_none() => *root = Some(archive),
+            _ => return Err(Error::corrupt("archive contains more than one root")),
         }
+        Ok(())
     }
 
-    pub fn write_to_disk(&self, path: &str) {
+    pub fn name(&self) -> &str {
         match self {
-            Archive::File { name, content } if fs::metadata(format!("{}/{}", path, name)).is_err() => {
-                fs::write(path.to_string() + "/" + name, content).unwrap_or(());
+            Archive::Directory { name, .. }
+            | Archive::File { name, .. }
+            | Archive::Symlink { name, .. }
+            | Archive::Hardlink { name, .. } => name,
+        }
+    }
+
+    /// Reads the file or directory at `path` into memory, see `stream_from_disk`.
+    pub fn read_from_disk(path: &str, follow_links: bool) -> Result<Archive> {
+        let mut serialized = Vec::new();
+        Self::stream_from_disk(path, follow_links, &mut serialized)?;
+        Self::deserialize(&serialized)
+    }
+
+    /// Writes the file or directory at `path` as entry stream into `output`, one file at a time,
+    /// and returns the index of the written entries and the paths of the skipped ones.
+    /// The path itself is always resolved, links below it are only followed if `follow_links` is set
+    /// and stored as links otherwise. Files with several hard links are stored once,
+    /// further occurrences become `Hardlink` entries. FIFOs, sockets and devices are skipped.
+    pub fn stream_from_disk(path: &str, follow_links: bool, output: &mut impl Write) -> Result<(Vec<IndexEntry>, Vec<String>)> {
+        let full_path = fs::canonicalize(path).with_path(path)?;
+        let dir_name = utf8_name(full_path.file_name().ok_or_else(|| Error::InvalidName(path.to_string()))?)?;
+        let metadata = fs::metadata(path).with_path(path)?;
+        if is_special(&metadata) {
+            return Err(Error::Io { path: path.to_string(), source: io::Error::new(io::ErrorKind::Unsupported, "not a file, directory or symbolic link") });
+        }
+        let mut walk = DiskWalk { follow_links, links: HashMap::new(), skipped: Vec::new(), output: EntryWriter::new(output) };
+        walk.entry(path, dir_name, metadata, &[])?;
+        Ok((walk.output.finish()?, walk.skipped))
+    }
+
+    /// Extracts the selected entries of an entry stream into the directory at `path` while reading it.
+    /// Parent directories of selected entries are created even if they are not selected themselves.
+    /// Entries that already exist are handled according to `options.on_conflict`.
+    /// Unless `options.allow_unsafe` is set, entries with unsafe paths and entries below symbolic links
+    /// created by the same extraction are refused, so nothing is written outside of `path`.
+    /// Hard links always follow the file they point to in the stream. Directory metadata is restored
+    /// at the very end since creating entries inside a directory changes its modification time.
+    pub fn stream_to_disk(input: impl Read, path: &str, selection: &Selection, options: ExtractOptions) -> Result<ExtractSummary> {
+        let mut extractor = Extractor {
+            reader: EntryReader::new(input),
+            root: path.to_string(),
+            options,
+            directories: Vec::new(),
+            renamed: HashMap::new(),
+            links: HashSet::new(),
+            skipped: Vec::new(),
+            summary: ExtractSummary::default(),
+        };
+        // Directories that are not selected themselves, they are created once a selected entry inside them appears.
+        let mut pending: Vec<Entry> = Vec::new();
+        while let Some(entry) = extractor.reader.next_entry()? {
+            pending.retain(|dir| entry.path().starts_with(&(dir.path().to_string() + "/")));
+            if selection.selects(entry.path()) {
+                for dir in pending.drain(..) {
+                    extractor.extract(dir)?;
+                }
+                extractor.extract(entry)?;
+            } else if let Entry::File { size, .. } = entry {
+                extractor.reader.skip_content(size)?;
+            } else if let Entry::Directory { .. } = entry {
+                pending.push(entry);
+            }
+        }
+        for (dir_path, metadata) in extractor.directories.iter().rev() {
+            metadata.apply(Path::new(dir_path));
+        }
+        match selection.unused().first() {
+            Some(pattern) => Err(Error::NotFound(pattern.to_string())),
+            None => Ok(extractor.summary),
+        }
+    }
+
+    /// Writes the content of the selected files of an entry stream to `output`, one after another.
+    /// The content of hard links is only in the stream if it comes from `IndexedArchive::select_contents`.
+    pub fn cat_stream(input: impl Read, selection: &Selection, output: &mut impl Write) -> Result<()> {
+        let mut reader = EntryReader::new(input);
+        while let Some(entry) = reader.next_entry()? {
+            match entry {
+                Entry::File { path, size, .. } => {
+                    let selected = selection.selects(&path);
+                    let intact = match selected {
+                        true => reader.copy_content(size, output, "stdout")?,
+                        false => reader.skip_content(size)?,
+                    };
+                    if selected && !intact {
+                        return Err(Error::Checksum(path));
+                    }
+                },
+                Entry::Hardlink { path, target } if selection.selects(&path) => return Err(Error::UnresolvedLink { path, target }),
+                _ => {},
+            }
+        }
+        output.flush().with_path("stdout")?;
+        match selection.unused().first() {
+            Some(pattern) => Err(Error::NotFound(pattern.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    /// Reads an entry stream without writing anything and returns the paths of all files
+    /// whose content does not match its checksum. If the archive has an index, it has to
+    /// describe the same frames and entries.
+    pub fn verify_stream<R: Read>(input: FrameReader<R>) -> Result<Vec<String>> {
+        let mut reader = EntryReader::new(input);
+        let mut damaged = Vec::new();
+        let mut entries = Vec::new();
+        while let Some(entry) = reader.next_entry()? {
+            let content_offset = reader.input.position();
+            if let Entry::File { path, size, .. } = &entry {
+                if !reader.skip_content(*size)? {
+                    damaged.push(path.clone());
+                }
+            }
+            entries.push(IndexEntry { entry, content_offset });
+        }
+        if let Some(index) = reader.input.read_index()? {
+            let mismatch = (0..entries.len().max(index.entries.len())).find(|&i| entries.get(i) != index.entries.get(i));
+            if let Some(i) = mismatch {
+                let entry = entries.get(i).or(index.entries.get(i)).unwrap();
+                return Err(Error::corrupt(format!("the index does not match the entry {}", entry.entry.path())));
+            }
+        }
+        Ok(damaged)
+    }
+
+}
+
+/// How an existing entry is dealt with, see `OnConflict`.
+enum Resolution {
+    Skip,
+    Replace,
+    Rename(String),
+}
+
+/// The state of `Archive::stream_from_disk` while it walks the tree.
+struct DiskWalk<W: Write> {
+    follow_links: bool,
+    /// Archive paths of the files with several hard links seen so far.
+    links: HashMap<FileId, String>,
+    /// Disk paths of the FIFOs, sockets and devices left out.
+    skipped: Vec<String>,
+    output: EntryWriter<W>,
+}
+
+impl<W: Write> DiskWalk<W> {
+    fn entry(&mut self, path: &str, relative: &str, fs_metadata: fs::Metadata, ancestors: &[FileId]) -> Result<()> {
+        let metadata = Metadata::from_fs(&fs_metadata);
+        let id = file_id(&fs_metadata);
+
+        if is_special(&fs_metadata) {
+            self.skipped.push(path.to_string());
+            return Ok(());
+        }
+
+        let is_loop = fs_metadata.is_dir() && id.is_some_and(|id| ancestors.contains(&id));
+        if fs_metadata.is_symlink() || is_loop {
+            let target = fs::read_link(path).with_path(path)?;
+            return self.output.write_entry(Entry::Symlink {
+                path: relative.to_string(),
+                metadata,
+                target: utf8_name(target.as_os_str())?.to_string(),
+            })
+        }
+
+        if fs_metadata.is_file() {
+            if let (Some(id), true) = (id, link_count(&fs_metadata) > 1) {
+                if let Some(target) = self.links.get(&id) {
+                    return self.output.write_entry(Entry::Hardlink { path: relative.to_string(), target: target.clone() });
+                }
+                self.links.insert(id, relative.to_string());
+            }
+            let size = fs_metadata.len();
+            self.output.write_entry(Entry::File { path: relative.to_string(), metadata, size })?;
+            let mut file = fs::File::open(path).with_path(path)?.take(size);
+            let mut checksum = 0;
+            let mut buffer = vec![0; 1 << 16];
+            let mut written = 0;
+            while written < size {
+                let len = file.read(&mut buffer).with_path(path)?;
+                if len == 0 {
+                    return Err(Error::Io { path: path.to_string(), source: io::Error::new(io::ErrorKind::UnexpectedEof, "file shrank while reading") });
+                }
+                checksum = crc32c::crc32c_append(checksum, &buffer[..len]);
+                self.output.write_content(&buffer[..len])?;
+                written += len as u64;
+            }
+            return self.output.write_content(&checksum.to_le_bytes());
+        }
+
+        self.output.write_entry(Entry::Directory { path: relative.to_string(), metadata })?;
+        let mut children = fs::read_dir(path).with_path(path)?
+            .map(|entry| Ok(utf8_name(&entry.with_path(path)?.file_name())?.to_string()))
+            .collect::<Result<Vec<_>>>()?;
+        children.sort();
+        let ancestors = ancestors.iter().copied().chain(id).collect::<Vec<_>>();
+        for child_name in children {
+            let child_path = path.to_string() + "/" + &child_name;
+            let child_metadata = match self.follow_links {
+                // Dangling links can not be followed and are kept as links.
+                true => fs::metadata(&child_path).or_else(|_| fs::symlink_metadata(&child_path)),
+                false => fs::symlink_metadata(&child_path),
+            }.with_path(&child_path)?;
+            self.entry(&child_path, &(relative.to_string() + "/" + &child_name), child_metadata, &ancestors)?;
+        }
+        Ok(())
+    }
+
+}
+
+struct Extractor<R: Read> {
+    reader: EntryReader<R>,
+    root: String,
+    options: ExtractOptions,
+    /// Created directories, their metadata is applied once everything is extracted.
+    directories: Vec<(String, Metadata)>,
+    /// Archive paths of renamed entries and the path they were extracted to.
+    renamed: HashMap<String, String>,
+    /// Symbolic links created so far, nothing is extracted through them.
+    links: HashSet<String>,
+    /// Archive paths of directories that were skipped because something else is in their place,
+    /// everything inside them is skipped as well.
+    skipped: Vec<String>,
+    summary: ExtractSummary,
+}
+
+impl<R: Read> Extractor<R> {
+    /// The path an archive path is extracted to, taking renamed parent directories into account.
+    fn disk_path(&self, path: &str) -> String {
+        let renamed = std::iter::once(path.len())
+            .chain(path.rmatch_indices('/').map(|(i, _)| i))
+            .find_map(|end| self.renamed.get(&path[..end]).map(|disk| disk.clone() + &path[end..]));
+        renamed.unwrap_or_else(|| match path.starts_with('/') {
+            // Only reachable with `allow_unsafe`.
+            true => path.to_string(),
+            false => self.root.clone() + "/" + path,
+        })
+    }
+
+    fn resolve(&mut self, entry: &Entry, entry_path: &str, existing: &fs::Metadata) -> Result<Resolution> {
+        let replace = match self.options.on_conflict {
+            OnConflict::Skip => false,
+            OnConflict::Overwrite => true,
+            OnConflict::Newer => {
+                let mtime = match entry {
+                    Entry::Directory { metadata, .. } | Entry::File { metadata, .. } | Entry::Symlink { metadata, .. } => Some(metadata.mtime),
+                    Entry::Hardlink { .. } => None,
+                };
+                let existing_mtime = existing.modified().ok();
+                mtime.zip(existing_mtime).is_some_and(|(mtime, existing)| mtime.to_system_time() > existing)
             },
-            Archive::Directory { name, children } if fs::metadata(format!("{}/{}", path, name)).is_err() => {
-                fs::create_dir(path.to_string() + "/" + name).unwrap_or(());
-                children.par_iter().for_each(|child| child.write_to_disk(&(path.to_string() + "/" + name)));
+            OnConflict::Rename => {
+                let free = (1..).map(|n| format!("{}.{}", entry_path, n))
+                    .find(|candidate| fs::symlink_metadata(candidate).is_err())
+                    .unwrap();
+                return Ok(Resolution::Rename(free));
             },
-            Archive::File { name, .. } | Archive::Directory { name, .. } => println!("{} existiert bereits", name),
+            OnConflict::Abort => return Err(Error::AlreadyExists(entry_path.to_string())),
+        };
+        if replace && existing.is_dir() {
+            self.summary.events.push(ExtractEvent::DirectoryInTheWay(entry.path().to_string()));
+            return Ok(Resolution::Skip);
         }
+        Ok(if replace { Resolution::Replace } else { Resolution::Skip })
     }
 
-}
\ No newline at end of file
+    /// Refuses paths leaving the extraction directory, see `validate_path`.
+    fn check_path(&self, path: &str, disk_path: &str) -> Result<()> {
+        if self.options.allow_unsafe {
+            return Ok(());
+        }
+        validate_path(path)?;
+        match disk_path.match_indices('/').any(|(i, _)| self.links.contains(&disk_path[..i])) {
+            true => Err(Error::UnsafePath { path: path.to_string(), reason: "it is below an extracted symbolic link" }),
+            false => Ok(()),
+        }
+    }
+
+    /// Whether the entry at `path` is inside a skipped directory.
+    fn in_skipped(&self, path: &str) -> bool {
+        self.skipped.iter().any(|dir| path.starts_with(dir.as_str()) && path[dir.len()..].starts_with('/'))
+    }
+
+    fn extract(&mut self, entry: Entry) -> Result<()> {
+        if self.in_skipped(entry.path()) {
+            return self.skip(entry);
+        }
+        let mut entry_path = self.disk_path(entry.path());
+        self.check_path(entry.path(), &entry_path)?;
+        if let Entry::Hardlink { path, target } = &entry {
+            let target_path = self.disk_path(target);
+            self.check_path(target, &target_path)?;
+            if fs::symlink_metadata(&target_path).is_err() {
+                self.summary.events.push(ExtractEvent::MissingLinkTarget { path: path.clone(), target: target.clone() });
+                return self.skip(entry);
+            }
+        }
+        match fs::symlink_metadata(&entry_path) {
+            Ok(existing) if existing.is_dir() && matches!(entry, Entry::Directory { .. }) => {
+                self.summary.merged += 1;
+                return Ok(());
+            },
+            Ok(existing) => match self.resolve(&entry, &entry_path, &existing)? {
+                Resolution::Skip => {
+                    if let Entry::Directory { path, .. } = &entry {
+                        self.skipped.push(path.clone());
+                    }
+                    return self.skip(entry);
+                },
+                Resolution::Replace => {
+                    fs::remove_file(&entry_path).with_path(&entry_path)?;
+                    self.summary.overwritten += 1;
+                },
+                Resolution::Rename(free) => {
+                    self.summary.events.push(ExtractEvent::Renamed { path: entry.path().to_string(), to: free.clone() });
+                    self.renamed.insert(entry.path().to_string(), free.clone());
+                    entry_path = free;
+                    self.summary.renamed += 1;
+                },
+            },
+            Err(_) => self.summary.created += 1,
+        }
+        match entry {
+            Entry::Directory { metadata, .. } => {
+                fs::create_dir(&entry_path).with_path(&entry_path)?;
+                self.directories.push((entry_path, metadata));
+            },
+            Entry::File { metadata, size, .. } => {
+                let mut file = io::BufWriter::new(fs::File::create(&entry_path).with_path(&entry_path)?);
+                if !self.reader.copy_content(size, &mut file, &entry_path)? {
+                    return Err(Error::Checksum(entry_path));
+                }
+                file.flush().with_path(&entry_path)?;
+                drop(file);
+                metadata.apply(Path::new(&entry_path));
+            },
+            Entry::Symlink { metadata, target, .. } => {
+                create_symlink(&target, &entry_path)?;
+                metadata.apply_to_link(Path::new(&entry_path));
+                self.links.insert(entry_path);
+            },
+            Entry::Hardlink { target, .. } => {
+                fs::hard_link(self.disk_path(&target), &entry_path).with_path(&entry_path)?;
+            },
+        }
+        Ok(())
+    }
+
+    /// Leaves out an entry, its content is read past.
+    fn skip(&mut self, entry: Entry) -> Result<()> {
+        if let Entry::File { size, .. } = entry {
+            self.reader.skip_content(size)?;
+        }
+        self.summary.skipped += 1;
+        Ok(())
+    }
+}
@@ -1,6 +1,8 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+use crate::error::{Error, Result};
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
 pub struct BitBuffer {
     pub data: Vec<u8>,
     pub read_pos: usize,
@@ -12,8 +14,23 @@ impl BitBuffer {
         bincode::serialize(&self).unwrap()
     }
 
-    pub fn deserialize(input: &[u8]) -> Self {
-        bincode::deserialize(input).unwrap()
+    pub fn deserialize(input: &[u8]) -> Result<Self> {
+        let buffer: Self = bincode::deserialize(input)?;
+        buffer.validate()?;
+        Ok(buffer)
+    }
+
+    /// Checks that the bit counts of a deserialized buffer fit its data.
+    pub fn validate(&self) -> Result<()> {
+        if self.num_bits > self.data.len() * 8 || self.read_pos > self.num_bits {
+            return Err(Error::corrupt("bit buffer length exceeds its data"));
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub fn remaining(&self) -> usize {
+        self.num_bits - self.read_pos
     }
 
     pub fn new() -> Self {
@@ -26,7 +43,7 @@ impl BitBuffer {
 
     #[inline]
     pub fn write_bit(&mut self, bit: bool) {
-        if self.num_bits % 8 == 0 {
+        if self.num_bits.is_multiple_of(8) {
             self.data.push(0);
         }
         if bit {
@@ -50,40 +67,54 @@ impl BitBuffer {
     }
 
     #[inline]
-    pub fn read_bit(&mut self) -> Option<bool> {
+    pub fn read_bit(&mut self) -> Result<bool> {
         if self.read_pos >= self.num_bits {
-            return None;
+            return Err(Error::corrupt("unexpected end of bit stream"));
         }
         let bit = self.data[self.read_pos / 8] & (1 << (self.read_pos % 8)) != 0;
         self.read_pos += 1;
-        Some(bit)
+        Ok(bit)
     }
 
     #[inline]
-    pub fn read_byte(&mut self) -> Option<u8> {
-        if self.read_pos + 8 > self.data.len() * 8 {
-            return None;
-        }
-        let mut byte = 0;
-        for i in 0..8 {
-            if self.read_bit().unwrap() {
-                byte |= 1 << i;
-            }
+    pub fn read_byte(&mut self) -> Result<u8> {
+        Ok(self.read_bits(8)? as u8)
+    }
+
+    #[inline]
+    pub fn read_bits(&mut self, num_bits: u8) -> Result<u32> {
+        let bits = self.peek_bits(num_bits);
+        self.skip_bits(num_bits)?;
+        Ok(bits)
+    }
+
+    /// The next `num_bits` bits, at most 32, without reading them. Bits past the end are zero.
+    #[inline]
+    pub fn peek_bits(&self, num_bits: u8) -> u32 {
+        let byte = self.read_pos / 8;
+        let word = match self.data.get(byte..byte + 8) {
+            Some(word) => u64::from_le_bytes(word.try_into().unwrap()),
+            None => {
+                let mut word = [0u8; 8];
+                let tail = self.data.get(byte..).unwrap_or(&[]);
+                word[..tail.len()].copy_from_slice(tail);
+                u64::from_le_bytes(word)
+            },
+        };
+        let bits = word >> (self.read_pos % 8) & ((1u64 << num_bits) - 1);
+        // Deserialized data may have bits set past the end.
+        match self.num_bits - self.read_pos {
+            remaining if remaining < num_bits as usize => (bits & ((1u64 << remaining) - 1)) as u32,
+            _ => bits as u32,
         }
-        Some(byte)
     }
 
     #[inline]
-    pub fn read_bits(&mut self, num_bits: u8) -> Option<u32> {
+    pub fn skip_bits(&mut self, num_bits: u8) -> Result<()> {
         if self.read_pos + num_bits as usize > self.num_bits {
-            return None;
-        }
-        let mut bits = 0;
-        for i in 0..num_bits {
-            if self.read_bit().unwrap() {
-                bits |= 1 << i;
-            }
+            return Err(Error::corrupt("unexpected end of bit stream"));
         }
-        Some(bits)
+        self.read_pos += num_bits as usize;
+        Ok(())
     }
 }
\ No newline at end of file
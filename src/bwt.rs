@@ -0,0 +1,219 @@
+use rayon::iter::{IndexedParallelIterator, IntoParallelIterator, ParallelIterator};
+use serde::{Deserialize, Serialize};
+use suffix_array::SuffixArray;
+
+use crate::bitbuffer::BitBuffer;
+use crate::error::{Error, Result};
+use crate::huffman::CanonicalCode;
+use crate::lz_77::LZ77;
+
+/// Number of symbols that share one Huffman code.
+const BLOCK_SYMBOLS: usize = 1 << 14;
+/// A run of zeros is written in bijective base 2 with these two digits, other move-to-front
+/// indices `i` as `i + 1`.
+const RUN_A: usize = 0;
+const RUN_B: usize = 1;
+const SYMBOLS: usize = 257;
+
+/// Chunks compressed like bzip2: the Burrows–Wheeler transform sorts the bytes by what follows them,
+/// move-to-front turns the resulting repetitions into small numbers, mostly zeros, whose runs are
+/// shortened before Huffman coding. Slower than `LZ77`, but often smaller on text.
+///
+/// Every chunk is its length (32 bits), the row of the end of the chunk in the sorted suffixes
+/// (32 bits) and the number of blocks (32 bits). Blocks are like those of `Tokens`: symbol count (32 bits),
+/// code lengths as written by `CanonicalCode::write_lengths`, then the symbols.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Bwt {
+    chunks: Vec<BitBuffer>,
+    /// CRC32C of every uncompressed chunk.
+    checksums: Vec<u32>,
+}
+
+impl Bwt {
+    pub fn serialize(&self) -> Vec<u8> {
+        bincode::serialize(&self).unwrap()
+    }
+
+    pub fn deserialize(input: &[u8]) -> Result<Self> {
+        let bwt: Self = bincode::deserialize(input)?;
+        if bwt.chunks.len() != bwt.checksums.len() {
+            return Err(Error::corrupt("BWT chunk count does not match its checksums"));
+        }
+        bwt.chunks.iter().enumerate().try_for_each(|(i, chunk)| chunk.validate().map_err(|e| e.at(&format!("BWT chunk {}", i))))?;
+        Ok(bwt)
+    }
+
+    /// Transforms `input[start..]` in chunks of `2^bits - 2` bytes in parallel, the history before
+    /// `start` is not used. Huffman codes are at most `max_length` bits long.
+    pub fn encode(input: &[u8], start: usize, bits: u8, max_length: u8) -> Bwt {
+        let (chunks, checksums) = LZ77::map_chunks(input, start, bits, 0, |chunk, _| Self::encode_chunk(chunk, max_length))
+            .into_iter()
+            .unzip();
+        Bwt { chunks, checksums }
+    }
+
+    /// Decodes chunks of at most `chunk_size` bytes in parallel.
+    pub fn decode(self, chunk_size: usize, max_length: u8) -> Result<Vec<u8>> {
+        let chunks = self.chunks.into_par_iter().zip(self.checksums).enumerate().map(|(i, (chunk, checksum))| {
+            let output = Self::decode_chunk(chunk, chunk_size, max_length).map_err(|e| e.at(&format!("BWT chunk {}", i)))?;
+            match crc32c::crc32c(&output) == checksum {
+                true => Ok(output),
+                false => Err(Error::Checksum(format!("BWT chunk {}", i))),
+            }
+        }).collect::<Result<Vec<_>>>()?;
+        Ok(chunks.concat())
+    }
+
+    fn encode_chunk(input: &[u8], max_length: u8) -> BitBuffer {
+        let (transformed, primary) = transform(input);
+        let symbols = zero_runs(&move_to_front(&transformed));
+
+        let mut output = BitBuffer::new();
+        output.write_bits(input.len() as u32, 32);
+        output.write_bits(primary as u32, 32);
+        let blocks = symbols.chunks(BLOCK_SYMBOLS).collect::<Vec<_>>();
+        output.write_bits(blocks.len() as u32, 32);
+        for block in blocks {
+            let mut counts = vec![0u64; SYMBOLS];
+            for &symbol in block {
+                counts[symbol as usize] += 1;
+            }
+            let code = CanonicalCode::from_counts(&counts, max_length);
+            output.write_bits(block.len() as u32, 32);
+            code.write_lengths(&mut output);
+            for &symbol in block {
+                code.write(symbol as usize, &mut output);
+            }
+        }
+        output
+    }
+
+    fn decode_chunk(mut input: BitBuffer, chunk_size: usize, max_length: u8) -> Result<Vec<u8>> {
+        let len = input.read_bits(32)? as usize;
+        let primary = input.read_bits(32)? as usize;
+        if len > chunk_size || primary > len {
+            return Err(Error::corrupt("BWT chunk too long"));
+        }
+        // Move-to-front indices with their zero runs expanded.
+        let mut indices = Vec::with_capacity(len);
+        let mut run = 0;
+        let mut weight = 1;
+        for _ in 0..input.read_bits(32)? {
+            let count = input.read_bits(32)?;
+            if count as usize > BLOCK_SYMBOLS {
+                return Err(Error::corrupt("BWT block too long"));
+            }
+            let code = CanonicalCode::read_lengths(&mut input, SYMBOLS, max_length)?.decoder();
+            for _ in 0..count {
+                let symbol = code.read(&mut input)?;
+                if symbol <= RUN_B {
+                    run += weight << symbol;
+                    weight <<= 1;
+                    if run > len - indices.len() {
+                        return Err(Error::corrupt("BWT run too long"));
+                    }
+                    continue;
+                }
+                if indices.len() + run >= len {
+                    return Err(Error::corrupt("BWT chunk longer than given"));
+                }
+                indices.resize(indices.len() + run, 0);
+                indices.push((symbol - 1) as u8);
+                (run, weight) = (0, 1);
+            }
+        }
+        indices.resize(indices.len() + run, 0);
+        if indices.len() != len {
+            return Err(Error::corrupt("BWT chunk shorter than given"));
+        }
+        inverse_transform(&undo_move_to_front(&indices), primary)
+    }
+}
+
+/// The byte before every suffix of `input` in sorted order, and the row of the whole input, whose
+/// byte before is the end. That row is left out.
+fn transform(input: &[u8]) -> (Vec<u8>, usize) {
+    let (_, suffix_array) = SuffixArray::new(input).into_parts();
+    let mut primary = 0;
+    let mut output = Vec::with_capacity(input.len());
+    for (row, &suffix) in suffix_array.iter().enumerate() {
+        match suffix {
+            0 => primary = row,
+            suffix => output.push(input[suffix as usize - 1]),
+        }
+    }
+    (output, primary)
+}
+
+/// Recovers the input from back to front: row 0 is the empty suffix, and the suffix one byte longer
+/// than that of a row is found from how many equal bytes come before it.
+fn inverse_transform(transformed: &[u8], primary: usize) -> Result<Vec<u8>> {
+    let byte = |row: usize| transformed[row - (row > primary) as usize];
+    let mut starts = [0u32; 256];
+    for &c in transformed {
+        starts[c as usize] += 1;
+    }
+    // The end sorts before every byte.
+    let mut start = 1;
+    for count in &mut starts {
+        (*count, start) = (start, start + *count);
+    }
+    let mut next = vec![0u32; transformed.len() + 1];
+    for (row, next) in next.iter_mut().enumerate().filter(|&(row, _)| row != primary) {
+        let c = byte(row) as usize;
+        *next = starts[c];
+        starts[c] += 1;
+    }
+    let mut output = vec![0u8; transformed.len()];
+    let mut row = 0;
+    for c in output.iter_mut().rev() {
+        if row == primary {
+            return Err(Error::corrupt("BWT reaches the end early"));
+        }
+        *c = byte(row);
+        row = next[row] as usize;
+    }
+    Ok(output)
+}
+
+fn move_to_front(input: &[u8]) -> Vec<u8> {
+    let mut order: [u8; 256] = std::array::from_fn(|i| i as u8);
+    input.iter().map(|&c| {
+        let index = order.iter().position(|&o| o == c).unwrap();
+        order.copy_within(..index, 1);
+        order[0] = c;
+        index as u8
+    }).collect()
+}
+
+fn undo_move_to_front(indices: &[u8]) -> Vec<u8> {
+    let mut order: [u8; 256] = std::array::from_fn(|i| i as u8);
+    indices.iter().map(|&index| {
+        let c = order[index as usize];
+        order.copy_within(..index as usize, 1);
+        order[0] = c;
+        c
+    }).collect()
+}
+
+/// Replaces runs of zeros with `RUN_A` and `RUN_B` digits, least significant first, and
+/// shifts the other indices up by one.
+fn zero_runs(indices: &[u8]) -> Vec<u16> {
+    let mut output = Vec::with_capacity(indices.len() / 2);
+    let mut run = 0usize;
+    for &index in indices.iter().chain(std::iter::once(&1)) {
+        if index == 0 {
+            run += 1;
+            continue;
+        }
+        while run > 0 {
+            run -= 1;
+            output.push(if run & 1 == 0 { RUN_A } else { RUN_B } as u16);
+            run >>= 1;
+        }
+        output.push(index as u16 + 1);
+    }
+    // The chained index only ends the last run.
+    output.pop();
+    output
+}
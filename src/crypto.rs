@@ -0,0 +1,146 @@
+use std::fmt;
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+
+use crate::error::{Error, Result};
+
+pub const SALT_LEN: usize = 16;
+/// Length of the authentication tag appended to every encrypted frame.
+pub const TAG_LEN: usize = 16;
+/// Largest Argon2 memory cost in KiB an archive may ask for, 4 GiB.
+pub const MAX_MEMORY: u32 = 1 << 22;
+/// Largest number of Argon2 iterations an archive may ask for.
+pub const MAX_ITERATIONS: u32 = 64;
+
+/// Identifies what is encrypted, every frame uses a different nonce derived from it.
+/// Since the nonce depends on the position, reordered or swapped frames fail to authenticate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameId {
+    Data(u64),
+    Index,
+    KeyCheck,
+}
+
+impl FrameId {
+    fn nonce(self) -> [u8; 12] {
+        let (kind, number) = match self {
+            FrameId::Data(number) => (0, number),
+            FrameId::Index => (1, 0),
+            FrameId::KeyCheck => (2, 0),
+        };
+        let mut nonce = [0; 12];
+        nonce[0] = kind;
+        nonce[4..].copy_from_slice(&number.to_le_bytes());
+        nonce
+    }
+}
+
+impl fmt::Display for FrameId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameId::Data(number) => write!(f, "frame {}", number),
+            FrameId::Index => write!(f, "index"),
+            FrameId::KeyCheck => write!(f, "key check"),
+        }
+    }
+}
+
+/// Key derivation parameters and password check of an encrypted archive, stored in the header.
+///
+/// The key is derived from the password with Argon2id, every frame is encrypted
+/// with ChaCha20-Poly1305 after it is compressed.
+///
+/// Layout, all integers little endian:
+/// salt (16) | memory in KiB (4) | iterations (4) | parallelism (4) | key check (16)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Encryption {
+    pub salt: [u8; SALT_LEN],
+    pub memory: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+    /// Tag of an empty message encrypted with the key, tells a wrong password apart from damage.
+    pub key_check: [u8; TAG_LEN],
+}
+
+impl Encryption {
+    pub const LEN: usize = SALT_LEN + 12 + TAG_LEN;
+
+    /// Derives a key from `password` with a fresh random salt and the default Argon2id parameters.
+    pub fn new(password: &[u8]) -> Result<(Encryption, Cipher)> {
+        let mut salt = [0; SALT_LEN];
+        getrandom::getrandom(&mut salt).map_err(|e| Error::Io { path: "random source".to_string(), source: e.into() })?;
+        let mut encryption = Encryption {
+            salt,
+            memory: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+            key_check: [0; TAG_LEN],
+        };
+        let cipher = encryption.derive(password)?;
+        encryption.key_check = cipher.seal(FrameId::KeyCheck, &[], &[]).try_into().unwrap();
+        Ok((encryption, cipher))
+    }
+
+    /// Derives the key from `password` and checks it against the key check.
+    pub fn cipher(&self, password: &[u8]) -> Result<Cipher> {
+        let cipher = self.derive(password)?;
+        match cipher.open(FrameId::KeyCheck, &[], &self.key_check) {
+            Ok(_) => Ok(cipher),
+            Err(_) => Err(Error::Password("wrong password or key file")),
+        }
+    }
+
+    fn derive(&self, password: &[u8]) -> Result<Cipher> {
+        let params = Params::new(self.memory, self.iterations, self.parallelism, Some(32))
+            .map_err(|e| Error::corrupt(format!("invalid key derivation parameters: {}", e)))?;
+        let mut key = [0; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(password, &self.salt, &mut key)
+            .map_err(|e| Error::corrupt(format!("key derivation failed: {}", e)))?;
+        Ok(Cipher(ChaCha20Poly1305::new(Key::from_slice(&key))))
+    }
+
+    pub fn serialize(&self, output: &mut Vec<u8>) {
+        output.extend_from_slice(&self.salt);
+        output.extend_from_slice(&self.memory.to_le_bytes());
+        output.extend_from_slice(&self.iterations.to_le_bytes());
+        output.extend_from_slice(&self.parallelism.to_le_bytes());
+        output.extend_from_slice(&self.key_check);
+    }
+
+    pub fn deserialize(input: &[u8; Self::LEN]) -> Encryption {
+        let u32_at = |pos: usize| u32::from_le_bytes(input[pos..pos + 4].try_into().unwrap());
+        Encryption {
+            salt: input[..SALT_LEN].try_into().unwrap(),
+            memory: u32_at(SALT_LEN),
+            iterations: u32_at(SALT_LEN + 4),
+            parallelism: u32_at(SALT_LEN + 8),
+            key_check: input[SALT_LEN + 12..].try_into().unwrap(),
+        }
+    }
+}
+
+impl fmt::Display for Encryption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ChaCha20-Poly1305, Argon2id with {} KiB, {} iterations, {} lanes", self.memory, self.iterations, self.parallelism)
+    }
+}
+
+/// Encrypts and authenticates frames with the key derived from the password.
+#[derive(Clone)]
+pub struct Cipher(ChaCha20Poly1305);
+
+impl Cipher {
+    /// Returns the encrypted `data` followed by the tag, `associated` is authenticated but not encrypted.
+    pub fn seal(&self, id: FrameId, associated: &[u8], data: &[u8]) -> Vec<u8> {
+        self.0.encrypt(Nonce::from_slice(&id.nonce()), Payload { msg: data, aad: associated })
+            .expect("encryption only fails for messages larger than 256 GiB")
+    }
+
+    pub fn open(&self, id: FrameId, associated: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        self.0.decrypt(Nonce::from_slice(&id.nonce()), Payload { msg: data, aad: associated })
+            .map_err(|_| Error::corrupt(format!("{}: authentication failed", id)))
+    }
+}
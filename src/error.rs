@@ -0,0 +1,129 @@
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum Error {
+    /// An I/O operation on the given path failed.
+    Io {
+        path: String,
+        source: io::Error,
+    },
+    /// The compressed data is truncated or malformed.
+    Corrupt(String),
+    /// The archive was written by an incompatible version of tmy.
+    UnsupportedVersion(u16),
+    /// The archive uses feature flags this version does not know.
+    UnsupportedFlags(u32),
+    /// The archive was compressed with an unknown codec.
+    UnsupportedCodec(u8),
+    /// The data of the named chunk or file does not match its stored checksum.
+    Checksum(String),
+    /// A requested path or pattern does not match any entry of the archive.
+    NotFound(String),
+    /// A file name can not be represented in the archive.
+    InvalidName(String),
+    /// An entry would be written outside of the extraction directory.
+    UnsafePath {
+        path: String,
+        reason: &'static str,
+    },
+    /// Compression options out of their valid range.
+    InvalidOptions(String),
+    /// The password is wrong or could not be read.
+    Password(&'static str),
+    /// An extracted entry already exists and the conflict policy is to abort.
+    AlreadyExists(String),
+    /// The content of a hard link is needed, but its file was passed in a stream without index.
+    UnresolvedLink {
+        path: String,
+        target: String,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn corrupt(message: impl Into<String>) -> Self {
+        Error::Corrupt(message.into())
+    }
+
+    /// Prefixes corruption messages with the location they were found at.
+    pub fn at(self, location: &str) -> Self {
+        match self {
+            Error::Corrupt(message) => Error::Corrupt(format!("{}: {}", location, message)),
+            Error::Checksum(inner) if inner.is_empty() => Error::Checksum(location.to_string()),
+            Error::Checksum(inner) => Error::Checksum(format!("{}, {}", location, inner)),
+            error => error,
+        }
+    }
+
+    /// Converts an error of an archive stream, unwrapping errors that were passed through `io::Error`.
+    pub fn from_stream(error: io::Error, path: &str) -> Self {
+        if error.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            return *error.into_inner().unwrap().downcast::<Error>().unwrap();
+        }
+        match error.kind() {
+            io::ErrorKind::UnexpectedEof => Error::corrupt("unexpected end of archive"),
+            _ => Error::Io { path: path.to_string(), source: error },
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io { path, source } => write!(f, "{}: {}", path, source),
+            Error::Corrupt(message) => write!(f, "corrupt archive: {}", message),
+            Error::UnsupportedVersion(version) => write!(f, "unsupported archive version {}", version),
+            Error::UnsupportedFlags(flags) => write!(f, "unsupported archive features {:#010x}", flags),
+            Error::UnsupportedCodec(id) => write!(f, "unsupported codec {}", id),
+            Error::Checksum(location) => write!(f, "checksum mismatch in {}", location),
+            Error::NotFound(pattern) => write!(f, "{}: not found in archive", pattern),
+            Error::InvalidName(name) => write!(f, "invalid file name {:?}", name),
+            Error::UnsafePath { path, reason } => write!(f, "refusing to extract {:?}, {}", path, reason),
+            Error::InvalidOptions(message) => write!(f, "invalid options: {}", message),
+            Error::Password(message) => write!(f, "{}", message),
+            Error::AlreadyExists(path) => write!(f, "{} already exists", path),
+            Error::UnresolvedLink { path, target } => write!(f, "{} is a hard link to {}, which is not selected", path, target),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<bincode::Error> for Error {
+    fn from(error: bincode::Error) -> Self {
+        match *error {
+            bincode::ErrorKind::Io(error) => Error::from_stream(error, "archive"),
+            error => Error::Corrupt(error.to_string()),
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(error: Error) -> Self {
+        io::Error::other(error)
+    }
+}
+
+/// Attaches the affected path to I/O errors.
+pub trait IoContext<T> {
+    fn with_path(self, path: impl AsRef<Path>) -> Result<T>;
+}
+
+impl<T> IoContext<T> for io::Result<T> {
+    fn with_path(self, path: impl AsRef<Path>) -> Result<T> {
+        self.map_err(|source| Error::Io {
+            path: path.as_ref().display().to_string(),
+            source,
+        })
+    }
+}
@@ -0,0 +1,274 @@
+use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
+use serde::{Deserialize, Serialize};
+
+use crate::bitbuffer::BitBuffer;
+use crate::error::{Error, Result};
+
+/// The frequencies of a chunk are normalized to sum to `2^TABLE_LOG`, the number of decoder states.
+const TABLE_LOG: u8 = 11;
+const TABLE_SIZE: usize = 1 << TABLE_LOG;
+/// Byte `i` of a chunk is coded in stream `i % STREAMS`, each with a state of its own, so the
+/// decoder works on independent chains.
+const STREAMS: usize = 4;
+
+/// Chunks of data coded with table-based asymmetric numeral systems, as in FSE. Like Huffman coding it
+/// needs one table lookup per byte, but spends fractions of bits like arithmetic coding.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ParallelFse {
+    chunks: Vec<FseChunk>,
+}
+
+impl ParallelFse {
+    pub fn serialize(&self) -> Vec<u8> {
+        bincode::serialize(&self).unwrap()
+    }
+
+    pub fn deserialize(input: &[u8]) -> Result<ParallelFse> {
+        let fse: Self = bincode::deserialize(input)?;
+        fse.chunks.iter().enumerate().try_for_each(|(i, chunk)| chunk.validate().map_err(|e| e.at(&format!("FSE chunk {}", i))))?;
+        Ok(fse)
+    }
+
+    /// Codes chunks of `2^bits - 1` bytes in parallel.
+    pub fn encode(input: &[u8], bits: u8) -> ParallelFse {
+        let chunks = input.chunks(2usize.pow(bits as u32) - 1)
+            .collect::<Vec<_>>()
+            .par_iter()
+            .map(|chunk| FseChunk::encode(chunk))
+            .collect::<Vec<_>>();
+        ParallelFse { chunks }
+    }
+
+    /// Decodes chunks that were encoded with the same `bits`.
+    pub fn decode(&self, bits: u8) -> Result<Vec<u8>> {
+        let chunks = self.chunks
+            .par_iter()
+            .enumerate()
+            .map(|(i, chunk)| chunk.decode(2usize.pow(bits as u32) - 1).map_err(|e| e.at(&format!("FSE chunk {}", i))))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(chunks.concat())
+    }
+}
+
+/// A non-empty chunk: the normalized frequencies as written by `write_frequencies` and the final state of every
+/// stream in `header`, then the streams, which the decoder reads in the opposite order they were written.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct FseChunk {
+    header: BitBuffer,
+    streams: Vec<BitBuffer>,
+    len: u64,
+    /// CRC32C of the uncompressed chunk.
+    checksum: u32,
+}
+
+impl FseChunk {
+    fn validate(&self) -> Result<()> {
+        if self.streams.len() != STREAMS {
+            return Err(Error::corrupt("wrong number of FSE streams"));
+        }
+        self.header.validate()?;
+        self.streams.iter().try_for_each(BitBuffer::validate)
+    }
+
+    fn encode(input: &[u8]) -> FseChunk {
+        let mut counts = [0u64; 256];
+        for &c in input {
+            counts[c as usize] += 1;
+        }
+        let frequencies = normalize(&counts);
+        let mut header = BitBuffer::new();
+        write_frequencies(&frequencies, &mut header);
+        let table = EncodingTable::new(&frequencies);
+
+        let streams = (0..STREAMS).map(|stream| {
+            // Symbols are encoded last to first, the decoder gets them back first to last. The bits are
+            // collected and written in reverse, so that the decoder reads the stream front to back.
+            let mut state = TABLE_SIZE as u32;
+            let mut emitted = Vec::with_capacity(input.len() / STREAMS + 1);
+            for &symbol in input.iter().skip(stream).step_by(STREAMS).rev() {
+                let (bits, count);
+                (state, bits, count) = table.encode(state, symbol);
+                emitted.push((bits, count));
+            }
+            header.write_bits(state - TABLE_SIZE as u32, TABLE_LOG);
+            let mut output = BitBuffer::new();
+            for &(bits, count) in emitted.iter().rev() {
+                output.write_bits(bits, count);
+            }
+            output
+        }).collect();
+
+        FseChunk { header, streams, len: input.len() as u64, checksum: crc32c::crc32c(input) }
+    }
+
+    /// Decodes a chunk of at most `max_len` bytes.
+    fn decode(&self, max_len: usize) -> Result<Vec<u8>> {
+        if self.len > max_len as u64 {
+            return Err(Error::corrupt("FSE chunk too long"));
+        }
+        let mut header = self.header.clone();
+        let table = DecodingTable::new(&read_frequencies(&mut header)?);
+        let mut states = [0usize; STREAMS];
+        for state in &mut states {
+            *state = header.read_bits(TABLE_LOG)? as usize;
+        }
+        let mut streams = self.streams.clone();
+        let mut output = vec![0u8; self.len as usize];
+        let mut chunks = output.chunks_exact_mut(STREAMS);
+        for bytes in &mut chunks {
+            for ((byte, state), stream) in bytes.iter_mut().zip(&mut states).zip(&mut streams) {
+                *byte = table.decode(state, stream)?;
+            }
+        }
+        for ((byte, state), stream) in chunks.into_remainder().iter_mut().zip(&mut states).zip(&mut streams) {
+            *byte = table.decode(state, stream)?;
+        }
+        if crc32c::crc32c(&output) != self.checksum {
+            return Err(Error::Checksum(String::new()));
+        }
+        Ok(output)
+    }
+}
+
+/// Scales the counts to sum to `TABLE_SIZE`, every occurring byte keeps at least 1. The slots left
+/// after rounding down go to the largest remainders, slots missing after rounding up the rare bytes are
+/// taken from the most frequent ones.
+fn normalize(counts: &[u64; 256]) -> [u32; 256] {
+    let total = counts.iter().sum::<u64>();
+    let mut frequencies = [0u32; 256];
+    if total == 0 {
+        return frequencies;
+    }
+    for (frequency, &count) in frequencies.iter_mut().zip(counts) {
+        if count > 0 {
+            *frequency = ((count * TABLE_SIZE as u64 / total) as u32).max(1);
+        }
+    }
+    let mut by_remainder = (0..256).filter(|&c| counts[c] > 0).collect::<Vec<_>>();
+    by_remainder.sort_by_key(|&c| std::cmp::Reverse(counts[c] * TABLE_SIZE as u64 % total));
+    // Rounding down loses less than one slot per byte, so a pass over them is enough.
+    let mut sum = frequencies.iter().sum::<u32>();
+    for &c in &by_remainder[..(TABLE_SIZE as u32).saturating_sub(sum) as usize] {
+        frequencies[c] += 1;
+    }
+    sum = sum.max(TABLE_SIZE as u32);
+    while sum > TABLE_SIZE as u32 {
+        let largest = (0..256).max_by_key(|&c| frequencies[c]).unwrap();
+        frequencies[largest] -= 1;
+        sum -= 1;
+    }
+    frequencies
+}
+
+/// Writes a bit for every byte whether it occurs and if so its frequency minus 1, in as many bits as the
+/// slots not yet assigned need.
+fn write_frequencies(frequencies: &[u32; 256], output: &mut BitBuffer) {
+    let mut remaining = TABLE_SIZE as u32;
+    for &frequency in frequencies {
+        output.write_bit(frequency > 0);
+        if frequency > 0 {
+            output.write_bits(frequency - 1, bits_for(remaining));
+            remaining -= frequency;
+        }
+    }
+}
+
+fn read_frequencies(input: &mut BitBuffer) -> Result<[u32; 256]> {
+    let mut frequencies = [0u32; 256];
+    let mut remaining = TABLE_SIZE as u32;
+    for frequency in &mut frequencies {
+        if input.read_bit()? {
+            *frequency = input.read_bits(bits_for(remaining))? + 1;
+            remaining = remaining.checked_sub(*frequency).ok_or_else(|| Error::corrupt("FSE frequencies exceed the table"))?;
+        }
+    }
+    if remaining != 0 {
+        return Err(Error::corrupt("FSE frequencies do not fill the table"));
+    }
+    Ok(frequencies)
+}
+
+/// Bits needed for the values `0..n`.
+fn bits_for(n: u32) -> u8 {
+    (32 - n.saturating_sub(1).leading_zeros()) as u8
+}
+
+/// The symbol of every state. Symbols are spread over the table so that the states of each are
+/// scattered evenly.
+fn spread(frequencies: &[u32; 256]) -> Vec<u8> {
+    let step = (TABLE_SIZE >> 1) + (TABLE_SIZE >> 3) + 3;
+    let mut symbols = vec![0u8; TABLE_SIZE];
+    let mut position = 0;
+    for (symbol, &frequency) in frequencies.iter().enumerate() {
+        for _ in 0..frequency {
+            symbols[position] = symbol as u8;
+            position = (position + step) & (TABLE_SIZE - 1);
+        }
+    }
+    symbols
+}
+
+struct EncodingTable {
+    /// The states of every symbol in table order, starting at `starts[symbol]`.
+    states: Vec<u32>,
+    starts: [u32; 256],
+    frequencies: [u32; 256],
+}
+
+impl EncodingTable {
+    fn new(frequencies: &[u32; 256]) -> Self {
+        let mut starts = [0u32; 256];
+        let mut start = 0;
+        for (symbol, &frequency) in frequencies.iter().enumerate() {
+            starts[symbol] = start;
+            start += frequency;
+        }
+        let mut next = starts;
+        let mut states = vec![0u32; TABLE_SIZE];
+        for (position, &symbol) in spread(frequencies).iter().enumerate() {
+            states[next[symbol as usize] as usize] = (TABLE_SIZE + position) as u32;
+            next[symbol as usize] += 1;
+        }
+        EncodingTable { states, starts, frequencies: *frequencies }
+    }
+
+    /// Encodes `symbol` from `state` in `TABLE_SIZE..2 * TABLE_SIZE`, returns the next state and
+    /// the low bits of `state` to write and their number.
+    #[inline]
+    fn encode(&self, state: u32, symbol: u8) -> (u32, u32, u8) {
+        let frequency = self.frequencies[symbol as usize];
+        // The reduced state has to land in `frequency..2 * frequency`.
+        let mut count = frequency.leading_zeros() - state.leading_zeros();
+        if state >> count < frequency {
+            count -= 1;
+        }
+        let reduced = state >> count;
+        let next = self.states[(self.starts[symbol as usize] + reduced - frequency) as usize];
+        (next, state & ((1 << count) - 1), count as u8)
+    }
+}
+
+/// For every state its symbol, how many bits to read and what to add them to for the next state.
+struct DecodingTable {
+    entries: Vec<(u8, u8, u16)>,
+}
+
+impl DecodingTable {
+    fn new(frequencies: &[u32; 256]) -> Self {
+        let mut next = *frequencies;
+        let entries = spread(frequencies).into_iter().map(|symbol| {
+            let x = next[symbol as usize];
+            next[symbol as usize] += 1;
+            let count = TABLE_LOG - (31 - x.leading_zeros()) as u8;
+            (symbol, count, ((x << count) - TABLE_SIZE as u32) as u16)
+        }).collect();
+        DecodingTable { entries }
+    }
+
+    #[inline]
+    fn decode(&self, state: &mut usize, input: &mut BitBuffer) -> Result<u8> {
+        let (symbol, count, base) = self.entries[*state];
+        *state = base as usize + input.read_bits(count)? as usize;
+        Ok(symbol)
+    }
+}
@@ -0,0 +1,325 @@
+use std::fmt;
+use std::io::Read;
+
+use crate::bwt::Bwt;
+use crate::crypto::{Encryption, MAX_ITERATIONS, MAX_MEMORY};
+use crate::error::{Error, Result};
+use crate::fse::ParallelFse;
+use crate::huffman::{ParrallelHuffman, CODE_LENGTH_LIMITS};
+use crate::lz_77::{Parser, LZ77};
+use crate::range_coder::RangeTokens;
+use crate::tokens::Tokens;
+
+pub const MAGIC: [u8; 4] = *b"\x7fTMY";
+pub const CURRENT_VERSION: u16 = 1;
+/// The archive ends with a central index, see `index::Index`.
+pub const FLAG_INDEX: u32 = 1;
+/// The frames are encrypted, the header contains the key derivation parameters.
+pub const FLAG_ENCRYPTED: u32 = 2;
+/// LZ77 may refer to data of earlier frames, the header contains the size of the window.
+pub const FLAG_LZ_WINDOW: u32 = 4;
+/// The header contains the limit for the length of Huffman codes, `crate::DEFAULT_CODE_LENGTH_LIMIT` without it.
+pub const FLAG_CODE_LENGTH_LIMIT: u32 = 8;
+/// Feature flags understood by this version, archives using other flags are rejected.
+pub const KNOWN_FLAGS: u32 = FLAG_INDEX | FLAG_ENCRYPTED | FLAG_LZ_WINDOW | FLAG_CODE_LENGTH_LIMIT;
+/// Length of the header up to and including the pipeline length.
+const FIXED_LEN: usize = 21;
+
+/// A compression stage. Stages are applied in pipeline order when compressing and reversed when decompressing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Lz77 = 0,
+    Huffman = 1,
+    /// Keeps the data as it is.
+    Store = 2,
+    /// LZ77 with its literals, lengths and distances Huffman coded, see `Tokens`.
+    Lz77Huffman = 3,
+    /// LZ77 with its tokens coded by an adaptive range coder, see `RangeTokens`.
+    Lz77Range = 4,
+    /// Bytes coded with asymmetric numeral systems in chunks like `Huffman`, see `ParallelFse`.
+    Fse = 5,
+    /// Burrows–Wheeler transform with move-to-front and Huffman coding in chunks like `Lz77`, see `Bwt`.
+    Bwt = 6,
+}
+
+impl Codec {
+    pub fn from_id(id: u8) -> Result<Codec> {
+        match id {
+            0 => Ok(Codec::Lz77),
+            1 => Ok(Codec::Huffman),
+            2 => Ok(Codec::Store),
+            3 => Ok(Codec::Lz77Huffman),
+            4 => Ok(Codec::Lz77Range),
+            5 => Ok(Codec::Fse),
+            6 => Ok(Codec::Bwt),
+            _ => Err(Error::UnsupportedCodec(id)),
+        }
+    }
+
+    /// Encodes `input[start..]`. The LZ77 stages may refer to the history `input[..start]`, which has to be
+    /// passed to `Factors::resolve` when decoding. The parser only affects how well LZ77 compresses, not the format.
+    pub fn encode(self, input: &[u8], start: usize, header: &Header, parser: Parser) -> Vec<u8> {
+        match self {
+            Codec::Lz77 => LZ77::encode_with_history(input, start, header.lz_bits, parser, header.window_size()).serialize(),
+            Codec::Huffman => ParrallelHuffman::encrypt(&input[start..], header.huffman_bits, header.code_length_limit()).serialize(),
+            Codec::Store => input[start..].to_vec(),
+            Codec::Lz77Huffman => Tokens::encode(input, start, header.lz_bits, parser, header.window_size(), header.code_length_limit()).serialize(),
+            Codec::Lz77Range => RangeTokens::encode(input, start, header.lz_bits, parser, header.window_size()).serialize(),
+            Codec::Fse => ParallelFse::encode(&input[start..], header.huffman_bits).serialize(),
+            Codec::Bwt => Bwt::encode(input, start, header.lz_bits, header.code_length_limit()).serialize(),
+        }
+    }
+
+    /// Decodes data without history, see `stream::decode_frame` for frames that may have one.
+    pub fn decode(self, input: &[u8], header: &Header) -> Result<Vec<u8>> {
+        match self {
+            Codec::Lz77 => LZ77::deserialize(input)?.read(0, header.window_size(), header.chunk_size())?.resolve(&[]),
+            Codec::Huffman => ParrallelHuffman::deserialize(input)?.decrypt(header.code_length_limit()),
+            Codec::Store => Ok(input.to_vec()),
+            Codec::Lz77Huffman => Tokens::deserialize(input)?.read(0, header.window_size(), header.chunk_size(), header.code_length_limit())?.resolve(&[]),
+            Codec::Lz77Range => RangeTokens::deserialize(input)?.read(0, header.window_size(), header.chunk_size())?.resolve(&[]),
+            Codec::Fse => ParallelFse::deserialize(input)?.decode(header.huffman_bits),
+            Codec::Bwt => Bwt::deserialize(input)?.decode(header.chunk_size(), header.code_length_limit()),
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Codec::Lz77 => write!(f, "LZ77"),
+            Codec::Huffman => write!(f, "Huffman"),
+            Codec::Store => write!(f, "Store"),
+            Codec::Lz77Huffman => write!(f, "LZ77+Huffman"),
+            Codec::Lz77Range => write!(f, "LZ77+Range"),
+            Codec::Fse => write!(f, "FSE"),
+            Codec::Bwt => write!(f, "BWT"),
+        }
+    }
+}
+
+/// The fixed header in front of every `.tmy` file, it is followed by the frames of the compressed entry stream.
+///
+/// Layout, all integers little endian:
+/// magic (4) | version (2) | flags (4) | lz bits (1) | huffman bits (1) | uncompressed size (8) |
+/// pipeline length (1) | codec ids (pipeline length) | encryption (only with `FLAG_ENCRYPTED`) |
+/// LZ77 window bits (1, only with `FLAG_LZ_WINDOW`) | Huffman code length limit (1, only with `FLAG_CODE_LENGTH_LIMIT`) |
+/// crc32c of all previous header bytes (4)
+///
+/// An uncompressed size of zero means unknown, the archive was written to a stream that could not be rewound.
+/// The layout of the encryption parameters is described at `Encryption`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub version: u16,
+    pub flags: u32,
+    pub lz_bits: u8,
+    pub huffman_bits: u8,
+    pub uncompressed_size: u64,
+    pub pipeline: Vec<Codec>,
+    pub encryption: Option<Encryption>,
+    /// LZ77 chunks may refer to `2^lz_window` bytes before them, 0 if frames are independent.
+    pub lz_window: u8,
+    /// Huffman codes are at most this many bits long, see `Header::code_length_limit`.
+    pub code_length_limit: u8,
+}
+
+impl Header {
+    pub fn new(pipeline: Vec<Codec>, lz_bits: u8, huffman_bits: u8, uncompressed_size: u64) -> Self {
+        Header {
+            version: CURRENT_VERSION,
+            flags: FLAG_INDEX,
+            lz_bits,
+            huffman_bits,
+            uncompressed_size,
+            pipeline,
+            encryption: None,
+            lz_window: 0,
+            code_length_limit: crate::DEFAULT_CODE_LENGTH_LIMIT,
+        }
+    }
+
+    pub fn set_encryption(&mut self, encryption: Encryption) {
+        self.flags |= FLAG_ENCRYPTED;
+        self.encryption = Some(encryption);
+    }
+
+    pub fn set_lz_window(&mut self, bits: u8) {
+        self.flags |= FLAG_LZ_WINDOW;
+        self.lz_window = bits;
+    }
+
+    pub fn set_code_length_limit(&mut self, bits: u8) {
+        self.flags |= FLAG_CODE_LENGTH_LIMIT;
+        self.code_length_limit = bits;
+    }
+
+    /// Longest Huffman code in bits. Without `FLAG_CODE_LENGTH_LIMIT` it is the default limit,
+    /// longer codes are rejected as corrupt.
+    pub fn code_length_limit(&self) -> u8 {
+        match self.flags & FLAG_CODE_LENGTH_LIMIT {
+            0 => crate::DEFAULT_CODE_LENGTH_LIMIT,
+            _ => self.code_length_limit,
+        }
+    }
+
+    /// Number of bytes in an LZ77 chunk.
+    pub fn chunk_size(&self) -> usize {
+        2usize.pow(self.lz_bits as u32) - 2
+    }
+
+    /// Number of bytes before a chunk that LZ77 may refer to.
+    pub fn window_size(&self) -> usize {
+        match self.flags & FLAG_LZ_WINDOW {
+            0 => 0,
+            _ => 1 << self.lz_window,
+        }
+    }
+
+    /// The serialized header as encrypted frames authenticate it. The uncompressed size is left out,
+    /// `Options::write_archive_seekable` only stores it after the frames are written.
+    pub fn authenticated(&self) -> Vec<u8> {
+        Header { uncompressed_size: 0, ..self.clone() }.serialize()
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut output = Vec::new();
+        output.extend_from_slice(&MAGIC);
+        output.extend_from_slice(&self.version.to_le_bytes());
+        output.extend_from_slice(&self.flags.to_le_bytes());
+        output.push(self.lz_bits);
+        output.push(self.huffman_bits);
+        output.extend_from_slice(&self.uncompressed_size.to_le_bytes());
+        output.push(self.pipeline.len() as u8);
+        output.extend(self.pipeline.iter().map(|&codec| codec as u8));
+        if let Some(encryption) = &self.encryption {
+            encryption.serialize(&mut output);
+        }
+        if self.flags & FLAG_LZ_WINDOW != 0 {
+            output.push(self.lz_window);
+        }
+        if self.flags & FLAG_CODE_LENGTH_LIMIT != 0 {
+            output.push(self.code_length_limit);
+        }
+        let checksum = crc32c::crc32c(&output);
+        output.extend_from_slice(&checksum.to_le_bytes());
+        output
+    }
+
+    /// Parses the header at the start of `input` and returns it together with its length in bytes.
+    pub fn deserialize(input: &[u8]) -> Result<(Header, usize)> {
+        if input.len() < MAGIC.len() || input[..MAGIC.len()] != MAGIC {
+            return Err(Error::corrupt("not a tmy archive"));
+        }
+        let mut reader = ByteReader { input, pos: MAGIC.len() };
+        let version = u16::from_le_bytes(reader.take()?);
+        if version > CURRENT_VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        let flags = u32::from_le_bytes(reader.take()?);
+        let [lz_bits, huffman_bits] = reader.take()?;
+        let uncompressed_size = u64::from_le_bytes(reader.take()?);
+        let [pipeline_len] = reader.take()?;
+        let pipeline = reader.take_slice(pipeline_len as usize)?.to_vec();
+        let encryption = match flags & FLAG_ENCRYPTED {
+            0 => None,
+            _ => Some(Encryption::deserialize(&reader.take()?)),
+        };
+        let [lz_window] = match flags & FLAG_LZ_WINDOW {
+            0 => [0],
+            _ => reader.take()?,
+        };
+        let [code_length_limit] = match flags & FLAG_CODE_LENGTH_LIMIT {
+            0 => [crate::DEFAULT_CODE_LENGTH_LIMIT],
+            _ => reader.take()?,
+        };
+        let header_len = reader.pos;
+        let checksum = u32::from_le_bytes(reader.take()?);
+        if checksum != crc32c::crc32c(&input[..header_len]) {
+            return Err(Error::corrupt("header checksum mismatch"));
+        }
+        if flags & !KNOWN_FLAGS != 0 {
+            return Err(Error::UnsupportedFlags(flags & !KNOWN_FLAGS));
+        }
+        let header = Header {
+            version,
+            flags,
+            lz_bits,
+            huffman_bits,
+            uncompressed_size,
+            pipeline: pipeline.into_iter().map(Codec::from_id).collect::<Result<Vec<_>>>()?,
+            encryption,
+            lz_window,
+            code_length_limit,
+        };
+        if !crate::CHUNK_BITS.contains(&lz_bits) || !crate::CHUNK_BITS.contains(&huffman_bits) {
+            return Err(Error::corrupt(format!("invalid chunk sizes of 2^{} and 2^{} bytes", lz_bits, huffman_bits)));
+        }
+        if flags & FLAG_LZ_WINDOW != 0 && !crate::CHUNK_BITS.contains(&lz_window) {
+            return Err(Error::corrupt(format!("invalid LZ77 window of 2^{} bytes", lz_window)));
+        }
+        if flags & FLAG_CODE_LENGTH_LIMIT != 0 && !CODE_LENGTH_LIMITS.contains(&code_length_limit) {
+            return Err(Error::corrupt(format!("invalid Huffman code length limit of {} bits", code_length_limit)));
+        }
+        if let Some(encryption) = &header.encryption {
+            if encryption.memory > MAX_MEMORY || encryption.iterations > MAX_ITERATIONS {
+                return Err(Error::corrupt(format!("key derivation needs too many resources, {}", encryption)));
+            }
+        }
+        Ok((header, reader.pos))
+    }
+
+    /// Reads just the header from the start of a stream.
+    pub fn read(input: &mut impl Read) -> Result<Header> {
+        let mut bytes = vec![0; FIXED_LEN];
+        input.read_exact(&mut bytes).map_err(|_| Error::corrupt("not a tmy archive"))?;
+        let pipeline_len = bytes[FIXED_LEN - 1] as usize;
+        let flags = u32::from_le_bytes(bytes[6..10].try_into().unwrap());
+        let encryption_len = if flags & FLAG_ENCRYPTED != 0 { Encryption::LEN } else { 0 };
+        let window_len = if flags & FLAG_LZ_WINDOW != 0 { 1 } else { 0 };
+        let limit_len = if flags & FLAG_CODE_LENGTH_LIMIT != 0 { 1 } else { 0 };
+        bytes.resize(FIXED_LEN + pipeline_len + encryption_len + window_len + limit_len + 4, 0);
+        input.read_exact(&mut bytes[FIXED_LEN..]).map_err(|_| Error::corrupt("truncated header"))?;
+        Ok(Self::deserialize(&bytes)?.0)
+    }
+}
+
+impl fmt::Display for Header {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pipeline = self.pipeline.iter().map(|codec| codec.to_string()).collect::<Vec<_>>().join(" -> ");
+        writeln!(f, "Format version    : {}", self.version)?;
+        writeln!(f, "Flags             : {:#010x}", self.flags)?;
+        writeln!(f, "Pipeline          : {}", pipeline)?;
+        writeln!(f, "LZ77 chunk size   : 2^{} bytes", self.lz_bits)?;
+        writeln!(f, "Huffman chunk size: 2^{} bytes", self.huffman_bits)?;
+        match self.window_size() {
+            0 => writeln!(f, "LZ77 window       : none")?,
+            _ => writeln!(f, "LZ77 window       : 2^{} bytes", self.lz_window)?,
+        }
+        writeln!(f, "Huffman code limit: {} bits", self.code_length_limit())?;
+        match &self.encryption {
+            Some(encryption) => writeln!(f, "Encryption        : {}", encryption)?,
+            None => writeln!(f, "Encryption        : none")?,
+        }
+        match self.uncompressed_size {
+            0 => write!(f, "Uncompressed size : unknown"),
+            size => write!(f, "Uncompressed size : {} bytes", size),
+        }
+    }
+}
+
+struct ByteReader<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn take_slice(&mut self, len: usize) -> Result<&'a [u8]> {
+        let slice = self.input.get(self.pos..self.pos + len).ok_or_else(|| Error::corrupt("truncated header"))?;
+        self.pos += len;
+        Ok(slice)
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take_slice(N)?.try_into().unwrap())
+    }
+}
@@ -1,11 +1,40 @@
 use std::cmp::Reverse;
+use std::collections::BinaryHeap;
 
-use indicatif::ParallelProgressIterator;
-use priority_queue::PriorityQueue;
-use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
 use serde::{Serialize, Deserialize};
 
-use crate::bitbuffer;
+use crate::bitbuffer::BitBuffer;
+use crate::error::{Error, Result};
+
+/// Longest code the decoder accepts, `CanonicalDecoder::read` looks at this many bits at once.
+pub const MAX_CODE_LENGTH: u8 = 20;
+/// Possible limits for the length of codes, the symbols of `tokens::Tokens` need at least 9 bits.
+/// Up to 20 bits the secondary decoding tables have at most `2^(20 - PRIMARY_BITS)` entries each.
+pub const CODE_LENGTH_LIMITS: std::ops::RangeInclusive<u8> = 9..=MAX_CODE_LENGTH;
+/// Code length symbols after the lengths themselves, as in DEFLATE: repeat the previous length 3 to 6
+/// times, and 3 to 10 or 11 to 138 zeros. Each is followed by the extra bits of its count.
+const REPEAT: usize = MAX_CODE_LENGTH as usize + 1;
+const ZEROS: usize = REPEAT + 1;
+const LONG_ZEROS: usize = REPEAT + 2;
+const LENGTH_SYMBOLS: usize = REPEAT + 3;
+/// Order in which the lengths of the code length code are written, likely ones first so that
+/// trailing zeros can be left out.
+const LENGTH_ORDER: [usize; LENGTH_SYMBOLS] = [
+    ZEROS, LONG_ZEROS, REPEAT, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+    16, 17, 18, 19, 20,
+];
+/// Bits of a length of the code length code, which is limited to fit them.
+const LENGTH_LENGTH_BITS: u8 = 4;
+const LENGTH_CODE_LIMIT: u8 = (1 << LENGTH_LENGTH_BITS) - 1;
+/// Bits of a code looked up at once, longer codes continue in a secondary table.
+const PRIMARY_BITS: u8 = 10;
+const PRIMARY_MASK: u32 = (1 << PRIMARY_BITS) - 1;
+/// Table entries hold the code length, or the bits of the secondary table with `LINK`, and above
+/// `ENTRY_SHIFT` the symbol or the offset of the secondary table. Entries of length 0 are invalid codes.
+const LENGTH_MASK: u32 = 0x1f;
+const LINK: u32 = 0x20;
+const ENTRY_SHIFT: u32 = 6;
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
 pub struct ParrallelHuffman {
@@ -17,192 +46,368 @@ impl ParrallelHuffman {
         bincode::serialize(&self).unwrap()
     }
 
-    pub fn deserialize(input: &[u8]) -> ParrallelHuffman {
-        bincode::deserialize(input).unwrap()
+    pub fn deserialize(input: &[u8]) -> Result<ParrallelHuffman> {
+        let huffman: Self = bincode::deserialize(input)?;
+        huffman.chunks.iter().enumerate().try_for_each(|(i, chunk)| chunk.data.validate().map_err(|e| e.at(&format!("Huffman chunk {}", i))))?;
+        Ok(huffman)
     }
 
-    pub fn encrypt(input: &Vec<u8>, bits: u8) -> ParrallelHuffman {
+    /// Codes chunks of `2^bits - 1` bytes with codes of at most `max_length` bits each.
+    pub fn encrypt(input: &[u8], bits: u8, max_length: u8) -> ParrallelHuffman {
         let chunk_size = 2usize.pow(bits as u32) - 1;
         let chunks = input.chunks(chunk_size)
             .collect::<Vec<_>>()
             .par_iter()
-            .progress()
-            .map(|chunk| Huffman::encrypt(&chunk.to_vec()))
+            .map(|chunk| Huffman::encrypt(chunk, max_length))
             .collect::<Vec<_>>();
         ParrallelHuffman { chunks }
     }
 
-    pub fn decrypt(&self) -> Vec<u8> {
-        self.chunks
+    /// Decodes the chunks, codes longer than `max_length` bits are rejected.
+    pub fn decrypt(&self, max_length: u8) -> Result<Vec<u8>> {
+        self.decrypt_chunks(max_length, Huffman::decrypt)
+    }
+
+    /// Like `decrypt`, but reads every code one bit at a time, see `CanonicalDecoder::read_bitwise`.
+    pub fn decrypt_bitwise(&self, max_length: u8) -> Result<Vec<u8>> {
+        self.decrypt_chunks(max_length, Huffman::decrypt_bitwise)
+    }
+
+    fn decrypt_chunks(&self, max_length: u8, decrypt: fn(&Huffman, u8) -> Result<Vec<u8>>) -> Result<Vec<u8>> {
+        let chunks = self.chunks
             .par_iter()
-            .progress()
-            .flat_map(|chunk| chunk.decrypt())
-            .collect()
+            .enumerate()
+            .map(|(i, chunk)| decrypt(chunk, max_length).map_err(|e| e.at(&format!("Huffman chunk {}", i))))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(chunks.concat())
     }
 }
 
+/// A chunk coded with a canonical Huffman code: the code lengths of all byte values as written by
+/// `CanonicalCode::write_lengths`, followed by the codes of the bytes.
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
 pub struct Huffman {
-    tree: Vec<u8>,
-    unused_bits: u8,
-    pub data: Vec<u8>,
+    data: BitBuffer,
+    /// CRC32C of the uncompressed chunk.
+    checksum: u32,
 }
 
 impl Huffman {
-    pub fn encrypt(input: &Vec<u8>) -> Huffman {
-        let tree = HuffmanTree::build_tree(&input);
-        let mut lookup = (0..256).map(|_| Vec::new()).collect::<Vec<_>>();
-        tree.build_map(vec![], &mut lookup);
-
-        let (count, data) = input
-            .into_iter()
-            .flat_map(|&c| &lookup[c as usize])
-            .fold((0usize,Vec::new()), |(indx, mut acc), c|{
-                if indx % 8 == 0 {
-                    acc.push(if *c {1u8} else {0u8});
-                } else if *c {
-                    *acc.last_mut().unwrap() |= 1 << (indx % 8);
-                }
-                (indx + 1, acc)
-            });
-
+    pub fn encrypt(input: &[u8], max_length: u8) -> Huffman {
+        let mut counts = [0u64; 256];
+        for &c in input {
+            counts[c as usize] += 1;
+        }
+        let code = CanonicalCode::from_counts(&counts, max_length);
+        let mut data = BitBuffer::new();
+        code.write_lengths(&mut data);
+        for &c in input {
+            code.write(c as usize, &mut data);
+        }
         Huffman {
-            tree: tree.better_serialize(),
-            unused_bits: match count % 8 {
-                0 => 0,
-                n => 8 - n as u8,
-            },
             data,
+            checksum: crc32c::crc32c(input),
         }
     }
 
-    pub fn decrypt(&self) -> Vec<u8> {
-        let tree = HuffmanTree::better_deserialize(&self.tree);
-        let data = &self.data;
-        let unused = self.unused_bits;
+    pub fn decrypt(&self, max_length: u8) -> Result<Vec<u8>> {
+        self.decode_with(max_length, CanonicalDecoder::read)
+    }
+
+    /// Like `decrypt`, but reads every code one bit at a time.
+    pub fn decrypt_bitwise(&self, max_length: u8) -> Result<Vec<u8>> {
+        self.decode_with(max_length, CanonicalDecoder::read_bitwise)
+    }
+
+    fn decode_with(&self, max_length: u8, read: fn(&CanonicalDecoder, &mut BitBuffer) -> Result<usize>) -> Result<Vec<u8>> {
+        let mut data = self.data.clone();
+        let decoder = CanonicalCode::read_lengths(&mut data, 256, max_length)?.decoder();
         let mut result = Vec::new();
-        let mut input = 1u32;
-        let map = tree.build_reverse_map();
-        for i in 0..data.len() * 8 - unused as usize {
-            let indx = i / 8;
-            let bit = (i % 8) as u8;
-            input = (input << 1) | ((data[indx] >> bit) & 1 ) as u32;
-            if let Some(c) = map[input as usize] {
-                result.push(c);
-                input = 1;
-            }
+        while data.remaining() > 0 {
+            result.push(read(&decoder, &mut data)? as u8);
+        }
+        if crc32c::crc32c(&result) != self.checksum {
+            return Err(Error::Checksum(String::new()));
         }
-        result
+        Ok(result)
     }
 }
 
-#[derive(PartialEq, Eq, Hash, Debug, Clone)]
-pub struct HuffmanTree {
-    pub children: Vec<HuffmanTree>,
-    pub character: Option<u8>,
+/// A canonical Huffman code over the symbols `0..lengths.len()`, described only by the code length of
+/// every symbol. Codes are assigned in order of length and then symbol, so the lengths are all a
+/// decoder needs. Symbols of length 0 do not occur.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CanonicalCode {
+    lengths: Vec<u8>,
+    codes: Vec<u32>,
 }
 
-impl HuffmanTree {
-    pub fn better_serialize(&self) -> Vec<u8> {
-        let mut bitbuffer = bitbuffer::BitBuffer::new();
-        self.beter_serialize_rec(&mut bitbuffer);
-        bitbuffer.serialize()
+impl CanonicalCode {
+    /// Builds a Huffman code for symbols occurring `counts[symbol]` times. If that has codes longer
+    /// than `max_length` bits, the best code within the limit is built instead.
+    pub fn from_counts(counts: &[u64], max_length: u8) -> CanonicalCode {
+        let used = (0..counts.len()).filter(|&symbol| counts[symbol] > 0).collect::<Vec<_>>();
+        assert!(used.len() <= 1 << max_length, "{} symbols do not fit codes of {} bits", used.len(), max_length);
+        let mut lengths = vec![0u8; counts.len()];
+        if let [symbol] = used[..] {
+            lengths[symbol] = 1;
+        } else if used.len() > 1 {
+            // Leaves are the used symbols, every merge adds a node whose parent is set once it is merged itself.
+            let mut parents = vec![0usize; 2 * used.len() - 1];
+            let mut queue = used.iter().enumerate()
+                .map(|(node, &symbol)| Reverse((counts[symbol], node)))
+                .collect::<BinaryHeap<_>>();
+            let mut next = used.len();
+            while let (Some(Reverse((left, a))), Some(Reverse((right, b)))) = (queue.pop(), queue.pop()) {
+                parents[a] = next;
+                parents[b] = next;
+                queue.push(Reverse((left + right, next)));
+                next += 1;
+            }
+            let mut depths = vec![0usize; parents.len()];
+            for node in (0..parents.len() - 1).rev() {
+                depths[node] = depths[parents[node]] + 1;
+            }
+            if depths[..used.len()].iter().any(|&depth| depth > max_length as usize) {
+                lengths = Self::package_merge(counts, &used, max_length);
+            } else {
+                for (node, &symbol) in used.iter().enumerate() {
+                    lengths[symbol] = depths[node] as u8;
+                }
+            }
+        }
+        Self::from_lengths(lengths, max_length).expect("Huffman code lengths are always valid")
     }
 
-    fn beter_serialize_rec(&self, bitbuffer: &mut bitbuffer::BitBuffer) {
-        match self.character {
-            Some(c) => {
-                bitbuffer.write_bit(true);
-                bitbuffer.write_byte(c);
+    /// The optimal lengths of at most `max_length` bits for the `used` symbols, found with the package-merge
+    /// algorithm: a symbol gets one bit for every time it is among the `2n - 2` cheapest items of the last list.
+    fn package_merge(counts: &[u64], used: &[usize], max_length: u8) -> Vec<u8> {
+        #[derive(Clone, Copy)]
+        enum Item {
+            Leaf(usize),
+            /// Items `2k` and `2k + 1` of the previous list.
+            Package(usize),
+        }
+        let mut leaves = used.iter().map(|&symbol| (counts[symbol], Item::Leaf(symbol))).collect::<Vec<_>>();
+        leaves.sort_by_key(|&(count, _)| count);
+        let mut lists = vec![leaves.clone()];
+        for _ in 1..max_length {
+            let previous = lists.last().unwrap();
+            let mut packages = previous.chunks_exact(2).enumerate().map(|(k, pair)| (pair[0].0 + pair[1].0, Item::Package(k))).peekable();
+            let mut list = Vec::with_capacity(leaves.len() + previous.len() / 2);
+            let mut leaves = leaves.iter().copied().peekable();
+            while let Some(next) = match (leaves.peek(), packages.peek()) {
+                (Some(leaf), Some(package)) if package.0 < leaf.0 => packages.next(),
+                (Some(_), _) => leaves.next(),
+                (None, _) => packages.next(),
+            } {
+                list.push(next);
+            }
+            lists.push(list);
+        }
+
+        let mut lengths = vec![0u8; counts.len()];
+        let mut selected = (0..2 * used.len() - 2).map(|i| (lists.len() - 1, i)).collect::<Vec<_>>();
+        while let Some((list, i)) = selected.pop() {
+            match lists[list][i].1 {
+                Item::Leaf(symbol) => lengths[symbol] += 1,
+                Item::Package(k) => selected.extend([(list - 1, 2 * k), (list - 1, 2 * k + 1)]),
             }
-            None => {
-                bitbuffer.write_bit(false);
-                self.children[0].beter_serialize_rec(bitbuffer);
-                self.children[1].beter_serialize_rec(bitbuffer);
+        }
+        lengths
+    }
+
+    /// Assigns the codes, fails if the lengths do not describe a prefix code or exceed `max_length` bits.
+    pub fn from_lengths(lengths: Vec<u8>, max_length: u8) -> Result<CanonicalCode> {
+        if lengths.iter().any(|&length| length > max_length.min(MAX_CODE_LENGTH)) {
+            return Err(Error::corrupt("huffman code too long"));
+        }
+        let mut codes = vec![0u32; lengths.len()];
+        let mut code = 0u64;
+        for length in 1..=MAX_CODE_LENGTH {
+            for symbol in (0..lengths.len()).filter(|&symbol| lengths[symbol] == length) {
+                if code >> length != 0 {
+                    return Err(Error::corrupt("huffman code lengths are oversubscribed"));
+                }
+                codes[symbol] = code as u32;
+                code += 1;
             }
+            code <<= 1;
         }
+        Ok(CanonicalCode { lengths, codes })
     }
 
-    pub fn better_deserialize(input: &[u8]) -> Self {
-        let mut bitbuffer = bitbuffer::BitBuffer::deserialize(input);
-        Self::better_deserialize_rec(&mut bitbuffer)
+    pub fn lengths(&self) -> &[u8] {
+        &self.lengths
     }
 
-    fn better_deserialize_rec(bitbuffer: &mut bitbuffer::BitBuffer) -> Self {
-        if let Some(true) = bitbuffer.read_bit() {
-            Self {
-                children: vec![],
-                character: bitbuffer.read_byte(),
+    /// Writes the code lengths run-length encoded and coded with a Huffman code of their own, whose
+    /// lengths come first in `LENGTH_ORDER`.
+    pub fn write_lengths(&self, output: &mut BitBuffer) {
+        let mut runs = Vec::new();
+        let mut i = 0;
+        while i < self.lengths.len() {
+            let length = self.lengths[i];
+            let run = self.lengths[i..].iter().take_while(|&&l| l == length).count();
+            match (length, run) {
+                (0, 11..) => runs.push((LONG_ZEROS, 7, run.min(138) - 11)),
+                (0, 3..) => runs.push((ZEROS, 3, run - 3)),
+                _ => runs.push((length as usize, 0, 0)),
             }
-        } else {
-            Self {
-                children: vec![
-                    Self::better_deserialize_rec(bitbuffer),
-                    Self::better_deserialize_rec(bitbuffer),
-                ],
-                character: None,
+            i += match runs.last().unwrap() {
+                (ZEROS, _, n) => n + 3,
+                (LONG_ZEROS, _, n) => n + 11,
+                _ => 1,
+            };
+            if length > 0 {
+                let mut repeats = run - 1;
+                while repeats >= 3 {
+                    runs.push((REPEAT, 2, repeats.min(6) - 3));
+                    i += repeats.min(6);
+                    repeats -= repeats.min(6);
+                }
             }
         }
-    }
 
-    pub fn from_counts(counts: [u64;256]) -> HuffmanTree {
-        let mut pq: PriorityQueue<Self, _, _> = PriorityQueue::new();
-        pq.extend(counts.into_iter().enumerate().map(|(c, count)| (Self {
-            children: vec![],
-            character: Some(c as u8),
-        }, Reverse(count))));
+        let mut counts = [0u64; LENGTH_SYMBOLS];
+        for &(symbol, _, _) in &runs {
+            counts[symbol] += 1;
+        }
+        let code = CanonicalCode::from_counts(&counts, LENGTH_CODE_LIMIT);
+        let sent = LENGTH_ORDER.iter().rposition(|&symbol| code.lengths[symbol] > 0).map_or(0, |last| last + 1);
+        output.write_bits(sent as u32, 6);
+        for &symbol in &LENGTH_ORDER[..sent] {
+            output.write_bits(code.lengths[symbol] as u32, LENGTH_LENGTH_BITS);
+        }
+        for (symbol, extra, count) in runs {
+            code.write(symbol, output);
+            output.write_bits(count as u32, extra);
+        }
+    }
 
-        while pq.len() > 1 {
-            let (left, count_left) = pq.pop().unwrap();
-            let (right, count_right) = pq.pop().unwrap();
-            pq.push(Self {
-                children: vec![left, right],
-                character: None,
-            }, Reverse(count_left.0 + count_right.0));
+    /// Reads the lengths of a code over `symbols` symbols written by `write_lengths`, failing for codes
+    /// longer than `max_length` bits.
+    pub fn read_lengths(input: &mut BitBuffer, symbols: usize, max_length: u8) -> Result<CanonicalCode> {
+        let sent = input.read_bits(6)? as usize;
+        if sent > LENGTH_SYMBOLS {
+            return Err(Error::corrupt("too many huffman code length symbols"));
         }
-        pq.pop().unwrap().0
+        let mut length_lengths = vec![0u8; LENGTH_SYMBOLS];
+        for &symbol in &LENGTH_ORDER[..sent] {
+            length_lengths[symbol] = input.read_bits(LENGTH_LENGTH_BITS)? as u8;
+        }
+        let decoder = CanonicalCode::from_lengths(length_lengths, LENGTH_CODE_LIMIT)?.decoder();
+        let mut lengths = Vec::with_capacity(symbols);
+        while lengths.len() < symbols {
+            let (length, count) = match decoder.read(input)? {
+                REPEAT => (*lengths.last().ok_or_else(|| Error::corrupt("huffman code length repeat without a length"))?, 3 + input.read_bits(2)?),
+                ZEROS => (0, 3 + input.read_bits(3)?),
+                LONG_ZEROS => (0, 11 + input.read_bits(7)?),
+                length => (length as u8, 1),
+            };
+            if lengths.len() + count as usize > symbols {
+                return Err(Error::corrupt("huffman code lengths exceed the alphabet"));
+            }
+            lengths.resize(lengths.len() + count as usize, length);
+        }
+        Self::from_lengths(lengths, max_length)
     }
 
-    pub fn build_tree(input: &Vec<u8>) -> HuffmanTree {
-        let mut counts = [0u64;256];
+    /// Writes the code of `symbol`, highest bit first.
+    #[inline]
+    pub fn write(&self, symbol: usize, output: &mut BitBuffer) {
+        let (code, length) = (self.codes[symbol], self.lengths[symbol]);
+        for bit in (0..length).rev() {
+            output.write_bit(code >> bit & 1 != 0);
+        }
+    }
 
-        for &e in input {
-            counts[e as usize] += 1;
+    pub fn decoder(&self) -> CanonicalDecoder {
+        let mut counts = vec![0u32; MAX_CODE_LENGTH as usize + 1];
+        for &length in &self.lengths {
+            counts[length as usize] += 1;
         }
+        counts[0] = 0;
+        let mut symbols = (0..self.lengths.len()).filter(|&symbol| self.lengths[symbol] > 0).collect::<Vec<_>>();
+        symbols.sort_by_key(|&symbol| self.lengths[symbol]);
 
-        Self::from_counts(counts)
+        // The stream holds the highest code bit first, so tables are indexed by the reversed codes.
+        let reversed = |symbol: usize| self.codes[symbol].reverse_bits() >> (32 - self.lengths[symbol] as u32);
+        let mut primary = vec![0u32; 1 << PRIMARY_BITS];
+        let mut longest = vec![0u8; 1 << PRIMARY_BITS];
+        for &symbol in symbols.iter().filter(|&&symbol| self.lengths[symbol] > PRIMARY_BITS) {
+            let prefix = (reversed(symbol) & PRIMARY_MASK) as usize;
+            longest[prefix] = longest[prefix].max(self.lengths[symbol]);
+        }
+        let mut secondary = Vec::new();
+        for (prefix, &length) in longest.iter().enumerate().filter(|&(_, &length)| length > 0) {
+            let bits = (length - PRIMARY_BITS) as u32;
+            primary[prefix] = (secondary.len() as u32) << ENTRY_SHIFT | LINK | bits;
+            secondary.resize(secondary.len() + (1 << bits), 0);
+        }
+        for &symbol in &symbols {
+            let (code, length) = (reversed(symbol), self.lengths[symbol]);
+            let entry = (symbol as u32) << ENTRY_SHIFT | length as u32;
+            if length <= PRIMARY_BITS {
+                for index in (code as usize..1 << PRIMARY_BITS).step_by(1 << length) {
+                    primary[index] = entry;
+                }
+            } else {
+                let link = primary[(code & PRIMARY_MASK) as usize];
+                let (offset, bits) = ((link >> ENTRY_SHIFT) as usize, link & LENGTH_MASK);
+                for index in ((code >> PRIMARY_BITS) as usize..1 << bits).step_by(1 << (length - PRIMARY_BITS)) {
+                    secondary[offset + index] = entry;
+                }
+            }
+        }
+        CanonicalDecoder { counts, symbols, primary, secondary }
     }
+}
 
-    fn build_reverse_map(&self) -> Vec<Option<u8>> {
-        let mut map = (0..256).map(|_| Vec::new()).collect::<Vec<_>>();
-        self.build_map(Vec::new(), &mut map);
-        
-        let max_len = map.iter().map(|v| v.len()).max().unwrap();
-        let mut result = vec![None; 2usize.pow(max_len as u32 + 1)];
-        for (c, path) in map.into_iter().enumerate() {
-            let indx = std::iter::once(true).chain(path.into_iter()).fold(0usize, |acc, b| (acc << 1) | if b {1} else {0});
-            result[indx] = Some(c as u8);
+/// Reads symbols of a `CanonicalCode`.
+pub struct CanonicalDecoder {
+    /// Number of codes of every length.
+    counts: Vec<u32>,
+    /// The symbols in code order.
+    symbols: Vec<usize>,
+    /// Entries for the next `PRIMARY_BITS` bits of the stream.
+    primary: Vec<u32>,
+    /// The tables for codes longer than `PRIMARY_BITS`, indexed by the bits after them.
+    secondary: Vec<u32>,
+}
+
+impl CanonicalDecoder {
+    /// Reads a symbol with at most two table lookups.
+    #[inline]
+    pub fn read(&self, input: &mut BitBuffer) -> Result<usize> {
+        let bits = input.peek_bits(MAX_CODE_LENGTH);
+        let mut entry = self.primary[(bits & PRIMARY_MASK) as usize];
+        if entry & LINK != 0 {
+            let index = (bits >> PRIMARY_BITS) & ((1 << (entry & LENGTH_MASK)) - 1);
+            entry = self.secondary[(entry >> ENTRY_SHIFT) as usize + index as usize];
+        }
+        match entry & LENGTH_MASK {
+            0 => Err(Error::corrupt("invalid huffman code")),
+            length => {
+                input.skip_bits(length as u8)?;
+                Ok((entry >> ENTRY_SHIFT) as usize)
+            },
         }
-        result
     }
 
-    fn build_map(&self, current_path: Vec<bool>, map: &mut Vec<Vec<bool>>) {
-        match self.character {
-            Some(c) => {
-                map[c as usize] = current_path;
-            }
-            None => {
-                self.children[0].build_map({
-                    let mut path = current_path.clone();
-                    path.push(false);
-                    path
-                }, map);
-                self.children[1].build_map({
-                    let mut path = current_path;
-                    path.push(true);
-                    path
-                }, map);
+    /// Reads a symbol one bit at a time, much slower than `read` and kept for comparison.
+    pub fn read_bitwise(&self, input: &mut BitBuffer) -> Result<usize> {
+        // `first` is the first code of the current length, `index` the position of its symbol.
+        let (mut code, mut first, mut index) = (0u32, 0u32, 0u32);
+        for &count in &self.counts[1..] {
+            code |= input.read_bit()? as u32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
             }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
         }
+        Err(Error::corrupt("invalid huffman code"))
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Range;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use serde::{Serialize, Deserialize};
+
+use crate::archive::{write_entry, Entry};
+use crate::crypto::{Cipher, FrameId};
+use crate::error::{Error, Result};
+use crate::pattern::Selection;
+use crate::header::Header;
+use crate::stream::{batch_size, decode_frame, decode_pending, history_len, read_frame, History, WINDOW_RESTART};
+
+/// Marks the trailer at the very end of an archive with an index.
+pub const TRAILER_MAGIC: [u8; 4] = *b"TMYI";
+/// Length of the trailer: index offset (8) | crc32c of the uncompressed index (4) | magic (4)
+pub const TRAILER_LEN: usize = 16;
+
+/// Where a frame is stored in the archive and which part of the entry stream it holds.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FrameInfo {
+    /// Position of the frame in the archive file.
+    pub offset: u64,
+    /// Position of the first byte of the frame in the entry stream.
+    pub start: u64,
+    /// Number of uncompressed bytes in the frame.
+    pub len: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub entry: Entry,
+    /// Position of the byte after the entry record in the entry stream,
+    /// where the content and checksum of a `File` entry start.
+    pub content_offset: u64,
+}
+
+/// The central index, a copy of every entry record and a table of all frames.
+///
+/// It is stored as an additional frame after the end marker, followed by the trailer.
+/// Readers that do not seek stop at the end marker and never see it.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Index {
+    pub frames: Vec<FrameInfo>,
+    pub entries: Vec<IndexEntry>,
+}
+
+impl Index {
+    pub fn serialize(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("the index is always serializable")
+    }
+
+    pub fn trailer(index_offset: u64, checksum: u32) -> [u8; TRAILER_LEN] {
+        let mut trailer = [0; TRAILER_LEN];
+        trailer[..8].copy_from_slice(&index_offset.to_le_bytes());
+        trailer[8..12].copy_from_slice(&checksum.to_le_bytes());
+        trailer[12..].copy_from_slice(&TRAILER_MAGIC);
+        trailer
+    }
+
+    /// Reads the index through the trailer at the end of `input`.
+    pub fn read<R: Read + Seek>(input: &mut R, header: &Header, cipher: Option<&Cipher>) -> Result<Index> {
+        let stream_error = |e| Error::from_stream(e, "archive");
+        input.seek(SeekFrom::End(-(TRAILER_LEN as i64))).map_err(|_| Error::corrupt("index is missing"))?;
+        let mut trailer = [0; TRAILER_LEN];
+        input.read_exact(&mut trailer).map_err(stream_error)?;
+        let (offset, checksum) = Self::parse_trailer(&trailer)?;
+        input.seek(SeekFrom::Start(offset)).map_err(stream_error)?;
+        let frame = read_frame(input)?.ok_or_else(|| Error::corrupt("index is missing"))?;
+        Self::decode(&decode_frame(frame, cipher, FrameId::Index, header)?, checksum)
+    }
+
+    /// Returns the offset of the index frame and the checksum of the index.
+    pub fn parse_trailer(trailer: &[u8; TRAILER_LEN]) -> Result<(u64, u32)> {
+        if trailer[12..] != TRAILER_MAGIC {
+            return Err(Error::corrupt("index is missing"));
+        }
+        Ok((u64::from_le_bytes(trailer[..8].try_into().unwrap()), u32::from_le_bytes(trailer[8..12].try_into().unwrap())))
+    }
+
+    /// Deserializes the decoded index frame, `checksum` comes from the trailer.
+    pub fn decode(data: &[u8], checksum: u32) -> Result<Index> {
+        if crc32c::crc32c(data) != checksum {
+            return Err(Error::Checksum("index".to_string()));
+        }
+        let index: Index = bincode::deserialize(data).map_err(|e| Error::from(e).at("index"))?;
+        index.check()?;
+        Ok(index)
+    }
+
+    /// Checks that the frames follow each other without gaps and every entry lies inside them,
+    /// so that positions computed from the index can not overflow.
+    fn check(&self) -> Result<()> {
+        let mut end = 0u64;
+        for (i, info) in self.frames.iter().enumerate() {
+            if info.start != end {
+                return Err(Error::corrupt(format!("frame {} does not follow the one before it in the index", i)));
+            }
+            end = info.start.checked_add(info.len).ok_or_else(|| Error::corrupt(format!("frame {} is too long", i)))?;
+        }
+        for entry in &self.entries {
+            // The content of a file is followed by its checksum.
+            let len = match entry.entry {
+                Entry::File { size, .. } => size.checked_add(4),
+                _ => Some(0),
+            };
+            if len.and_then(|len| entry.content_offset.checked_add(len)).is_none_or(|entry_end| entry_end > end) {
+                return Err(Error::corrupt(format!("{} lies outside the frames of the index", entry.entry.path())));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Random access to the entry stream of an archive through its index.
+pub struct IndexedArchive<R: Read + Seek> {
+    input: R,
+    index: Index,
+    header: Header,
+    cipher: Option<Cipher>,
+    /// The most recently decoded frames together with their number.
+    cache: Vec<(usize, Vec<u8>)>,
+}
+
+impl<R: Read + Seek> IndexedArchive<R> {
+    /// `header` is the header of the archive. Encrypted archives need the cipher derived from the password.
+    pub fn open(mut input: R, header: &Header, cipher: Option<Cipher>) -> Result<Self> {
+        let index = Index::read(&mut input, header, cipher.as_ref())?;
+        Ok(IndexedArchive { input, index, header: header.clone(), cipher, cache: Vec::new() })
+    }
+
+    pub fn index(&self) -> &Index {
+        &self.index
+    }
+
+    /// Returns up to `len` bytes at `start` of the entry stream,
+    /// ending early at the end of the frame that contains `start`.
+    fn read_at(&mut self, start: u64, len: u64) -> Result<&[u8]> {
+        let frames = &self.index.frames;
+        let past_end = || Error::corrupt("index points past the end of the archive");
+        let frame = frames.partition_point(|info| info.start.saturating_add(info.len) <= start);
+        let info = frames.get(frame).ok_or_else(past_end)?;
+        let from = start.checked_sub(info.start).ok_or_else(past_end)?;
+        let to = from.checked_add(len).ok_or_else(past_end)?.min(info.len);
+        let end = start.checked_add(len).ok_or_else(past_end)?;
+        if !self.cache.iter().any(|(i, _)| *i == frame) {
+            self.decode_frames(frame, end)?;
+        }
+        let (_, data) = self.cache.iter().find(|(i, _)| *i == frame).unwrap();
+        Ok(&data[from as usize..to as usize])
+    }
+
+    /// Decodes one batch of frames, starting at `first` and not going past the one containing `end`.
+    /// With an LZ77 window the frames from the last one before `first` that does not refer to earlier ones
+    /// are decoded first, a batch at a time. They are only needed as history and dropped once resolved.
+    fn decode_frames(&mut self, first: usize, end: u64) -> Result<()> {
+        let frames = &self.index.frames;
+        let begin = match self.header.window_size() {
+            0 => first,
+            _ => first - first % WINDOW_RESTART,
+        };
+        let last = (first..frames.len()).take(batch_size()).take_while(|&i| i == first || frames[i].start < end).last().unwrap_or(first);
+        let mut history = History::new(&self.header);
+        for start in (begin..first).step_by(batch_size()) {
+            self.decode_batch(start..first.min(start + batch_size()), &mut history)?;
+        }
+        self.cache = self.decode_batch(first..last + 1, &mut history)?;
+        Ok(())
+    }
+
+    /// Decodes the frames in `range` in parallel and resolves them in order with `history`.
+    fn decode_batch(&mut self, range: Range<usize>, history: &mut History) -> Result<Vec<(usize, Vec<u8>)>> {
+        let (frames, header, cipher) = (&self.index.frames, &self.header, self.cipher.as_ref());
+        let mut raw = Vec::new();
+        for i in range {
+            self.input.seek(SeekFrom::Start(frames[i].offset)).map_err(|e| Error::from_stream(e, "archive"))?;
+            let frame = read_frame(&mut self.input)?.ok_or_else(|| Error::corrupt(format!("frame {} is missing", i)))?;
+            raw.push((i, frame));
+        }
+        let pending = raw.into_par_iter()
+            .map(|(i, frame)| Ok((i, decode_pending(frame, cipher, FrameId::Data(i as u64), i + 1 == frames.len(), header, history_len(header, i))?)))
+            .collect::<Result<Vec<_>>>()?;
+        pending.into_iter()
+            .map(|(i, frame)| {
+                let data = history.resolve(i, frame)?;
+                match data.len() as u64 == frames[i].len {
+                    true => Ok((i, data)),
+                    false => Err(Error::corrupt(format!("frame {} does not match the index", i))),
+                }
+            })
+            .collect()
+    }
+
+    /// Returns an entry stream with every entry of the archive except the files that are not selected.
+    /// Entry records come from the index, so only frames with content of selected files are decoded.
+    /// A selected hard link to a file that is not selected becomes a copy of that file.
+    pub fn select(self, selection: &Selection) -> SelectedStream<R> {
+        self.select_links(selection, false)
+    }
+
+    /// Like `select`, but every selected hard link becomes a copy of its file, for `Archive::cat_stream`.
+    pub fn select_contents(self, selection: &Selection) -> SelectedStream<R> {
+        self.select_links(selection, true)
+    }
+
+    fn select_links(mut self, selection: &Selection, copy_links: bool) -> SelectedStream<R> {
+        let entries = std::mem::take(&mut self.index.entries);
+        let files = entries.iter()
+            .filter(|entry| matches!(entry.entry, Entry::File { .. }))
+            .map(|entry| (entry.entry.path().to_string(), entry.clone()))
+            .collect::<HashMap<_, _>>();
+        // The first copy of every file, further links to it point there.
+        let mut copies = HashMap::new();
+        let entries = entries.into_iter().filter_map(|entry| match &entry.entry {
+            Entry::File { path, .. } => selection.selects(path).then_some(entry),
+            Entry::Hardlink { path, target } if selection.selects(path) && (copy_links || !selection.selects(target)) => {
+                match (copy_links, copies.get(target), files.get(target)) {
+                    (false, Some(copy), _) => Some(IndexEntry { entry: Entry::Hardlink { path: path.clone(), target: String::clone(copy) }, ..entry }),
+                    (_, _, Some(IndexEntry { entry: Entry::File { metadata, size, .. }, content_offset })) => {
+                        copies.insert(target.clone(), path.clone());
+                        Some(IndexEntry { entry: Entry::File { path: path.clone(), metadata: metadata.clone(), size: *size }, content_offset: *content_offset })
+                    },
+                    _ => Some(entry),
+                }
+            },
+            _ => Some(entry),
+        }).collect::<Vec<_>>();
+        SelectedStream {
+            archive: self,
+            entries: entries.into_iter(),
+            record: Vec::new(),
+            pos: 0,
+            content: (0, 0),
+            finished: false,
+        }
+    }
+}
+
+/// An entry stream assembled from the index, see `IndexedArchive::select`.
+pub struct SelectedStream<R: Read + Seek> {
+    archive: IndexedArchive<R>,
+    entries: std::vec::IntoIter<IndexEntry>,
+    /// The serialized record of the current entry.
+    record: Vec<u8>,
+    pos: usize,
+    /// Position and length of the content of the current entry that is not read yet.
+    content: (u64, u64),
+    finished: bool,
+}
+
+impl<R: Read + Seek> Read for SelectedStream<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.pos < self.record.len() {
+                let len = buf.len().min(self.record.len() - self.pos);
+                buf[..len].copy_from_slice(&self.record[self.pos..self.pos + len]);
+                self.pos += len;
+                return Ok(len);
+            }
+            let (start, remaining) = self.content;
+            if remaining > 0 {
+                let data = self.archive.read_at(start, remaining)?;
+                let len = buf.len().min(data.len());
+                buf[..len].copy_from_slice(&data[..len]);
+                self.content = (start + len as u64, remaining - len as u64);
+                return Ok(len);
+            }
+            if self.finished {
+                return Ok(0);
+            }
+            self.record.clear();
+            self.pos = 0;
+            match self.entries.next() {
+                Some(IndexEntry { entry, content_offset }) => {
+                    write_entry(&mut self.record, Some(&entry))?;
+                    if let Entry::File { size, .. } = entry {
+                        // The content is followed by its checksum.
+                        self.content = (content_offset, size + 4);
+                    }
+                },
+                None => {
+                    write_entry(&mut self.record, None)?;
+                    self.finished = true;
+                },
+            }
+        }
+    }
+}
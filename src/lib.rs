@@ -0,0 +1,363 @@
+//! Folder archiver and compressor.
+//!
+//! [`Options`] configures the compression and writes archives of files and directories
+//! or compresses plain data. [`ArchiveReader`] reads both back. The modules expose the
+//! individual stages, from the entry stream down to the LZ77, BWT, Huffman, FSE and range coders.
+
+pub mod archive;
+pub mod metadata;
+pub mod lz_77;
+pub mod huffman;
+pub mod bwt;
+pub mod fse;
+pub mod bitbuffer;
+pub mod error;
+pub mod crypto;
+pub mod header;
+pub mod stream;
+pub mod index;
+pub mod listing;
+pub mod pattern;
+pub mod range_coder;
+pub mod tokens;
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+pub use archive::{Archive, ArchiveSummary, Entry, ExtractEvent, ExtractOptions, ExtractSummary, OnConflict};
+pub use error::{Error, Result};
+pub use header::{Codec, Header};
+pub use index::IndexedArchive;
+pub use lz_77::Parser;
+pub use pattern::Selection;
+pub use stream::FrameReader;
+
+use archive::EntryReader;
+use crypto::{Cipher, Encryption};
+use stream::FrameWriter;
+
+pub const DEFAULT_LZ_BITS: u8 = 28;
+pub const DEFAULT_HUFFMAN_BITS: u8 = 20;
+pub const DEFAULT_CODE_LENGTH_LIMIT: u8 = 15;
+/// Chunk sizes are given as powers of two in this range.
+pub const CHUNK_BITS: std::ops::RangeInclusive<u8> = 8..=31;
+/// Highest compression level, see `Options::level`.
+pub const MAX_LEVEL: u8 = 9;
+
+/// Compression settings, built by chaining the setters on `Options::new()`.
+#[derive(Debug, Clone)]
+pub struct Options {
+    lz_bits: u8,
+    huffman_bits: u8,
+    lz_window: u8,
+    code_length_limit: u8,
+    pipeline: Vec<Codec>,
+    parser: Parser,
+    fse: bool,
+    follow_links: bool,
+    password: Option<Vec<u8>>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            lz_bits: DEFAULT_LZ_BITS,
+            huffman_bits: DEFAULT_HUFFMAN_BITS,
+            lz_window: 0,
+            code_length_limit: DEFAULT_CODE_LENGTH_LIMIT,
+            pipeline: vec![Codec::Lz77, Codec::Huffman],
+            parser: Parser::Greedy,
+            fse: false,
+            follow_links: false,
+            password: None,
+        }
+    }
+}
+
+impl Options {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets pipeline, parser and chunk sizes from a preset, 0 stores the data as it is and
+    /// higher levels compress better but slower. Levels above `MAX_LEVEL` are treated as `MAX_LEVEL`.
+    /// The other setters change single settings of the preset when called afterwards.
+    pub fn level(self, level: u8) -> Self {
+        let (pipeline, parser, lz_bits, lz_window, huffman_bits) = match level {
+            0 => (vec![Codec::Store], Parser::Fast, 22, 0, DEFAULT_HUFFMAN_BITS),
+            1 => (vec![Codec::Lz77], Parser::Fast, 16, 0, DEFAULT_HUFFMAN_BITS),
+            2 => (vec![Codec::Lz77Huffman], Parser::Fast, 18, 0, 16),
+            3 => (vec![Codec::Lz77Huffman], Parser::Fast, 20, 0, 18),
+            4 => (vec![Codec::Lz77Huffman], Parser::Greedy, 20, 0, 18),
+            5 => (vec![Codec::Lz77Huffman], Parser::Lazy(1), 22, 0, 20),
+            6 => (vec![Codec::Lz77Huffman], Parser::Lazy(2), 24, 0, 20),
+            7 => (vec![Codec::Lz77Huffman], Parser::Lazy(2), 26, 0, 20),
+            8 => (vec![Codec::Lz77Huffman], Parser::Lazy(2), DEFAULT_LZ_BITS, 26, DEFAULT_HUFFMAN_BITS),
+            _ => (vec![Codec::Lz77Range], Parser::Optimal, 24, 24, DEFAULT_HUFFMAN_BITS),
+        };
+        self.pipeline(pipeline).parser(parser).lz_bits(lz_bits).lz_window(lz_window).huffman_bits(huffman_bits)
+    }
+
+    /// LZ77 chunks, and with them frames, hold `2^bits - 2` bytes.
+    pub fn lz_bits(mut self, bits: u8) -> Self {
+        self.lz_bits = bits;
+        self
+    }
+
+    /// Lets LZ77 refer to the `2^bits` bytes before every chunk, so repetitions between chunks are found.
+    /// Decoding has to resolve frames in order, and random access decodes up to `stream::WINDOW_RESTART`
+    /// frames to get to one. 0, the default, keeps frames independent.
+    pub fn lz_window(mut self, bits: u8) -> Self {
+        self.lz_window = bits;
+        self
+    }
+
+    /// Huffman chunks hold `2^bits` bytes.
+    pub fn huffman_bits(mut self, bits: u8) -> Self {
+        self.huffman_bits = bits;
+        self
+    }
+
+    /// Huffman codes are at most `bits` long. Lower limits cost a little compression on skewed
+    /// data and keep the decoding tables small.
+    pub fn code_length_limit(mut self, bits: u8) -> Self {
+        self.code_length_limit = bits;
+        self
+    }
+
+    /// The stages every frame is coded with, in order. Stages after the first are skipped
+    /// for frames they would make larger.
+    pub fn pipeline(mut self, pipeline: Vec<Codec>) -> Self {
+        self.pipeline = pipeline;
+        self
+    }
+
+    /// Replaces the Huffman stages of the pipeline with FSE, which decodes faster at about the same size.
+    /// `Codec::Lz77Huffman` becomes `Codec::Lz77` followed by `Codec::Fse`, FSE chunks hold `2^huffman_bits` bytes.
+    /// Pipelines without a Huffman stage, like those of levels 0, 1 and 9, are refused by `validate`.
+    pub fn fse(mut self) -> Self {
+        self.fse = true;
+        self
+    }
+
+    /// The pipeline with the Huffman stages replaced if `fse` was called.
+    fn coded_pipeline(&self) -> Vec<Codec> {
+        self.pipeline.iter()
+            .flat_map(|&codec| match (self.fse, codec) {
+                (true, Codec::Huffman) => vec![Codec::Fse],
+                (true, Codec::Lz77Huffman) => vec![Codec::Lz77, Codec::Fse],
+                (_, codec) => vec![codec],
+            })
+            .collect()
+    }
+
+    /// How LZ77 searches for matches, slower parsers find better ones.
+    pub fn parser(mut self, parser: Parser) -> Self {
+        self.parser = parser;
+        self
+    }
+
+    /// Follow symbolic links below the archived path instead of storing them as links.
+    pub fn follow_links(mut self, follow_links: bool) -> Self {
+        self.follow_links = follow_links;
+        self
+    }
+
+    /// Encrypt with a key derived from `password`.
+    pub fn password(mut self, password: impl Into<Vec<u8>>) -> Self {
+        self.password = Some(password.into());
+        self
+    }
+
+    /// Checks the options, every method writing data does this first.
+    pub fn validate(&self) -> Result<()> {
+        if !CHUNK_BITS.contains(&self.lz_bits) || !CHUNK_BITS.contains(&self.huffman_bits) {
+            return Err(Error::InvalidOptions(format!("chunk sizes have to be between 2^{} and 2^{}", CHUNK_BITS.start(), CHUNK_BITS.end())));
+        }
+        if self.lz_window != 0 && !CHUNK_BITS.contains(&self.lz_window) {
+            return Err(Error::InvalidOptions(format!("the LZ77 window has to be between 2^{} and 2^{}", CHUNK_BITS.start(), CHUNK_BITS.end())));
+        }
+        if !huffman::CODE_LENGTH_LIMITS.contains(&self.code_length_limit) {
+            return Err(Error::InvalidOptions(format!("the Huffman code length limit has to be between {} and {} bits", huffman::CODE_LENGTH_LIMITS.start(), huffman::CODE_LENGTH_LIMITS.end())));
+        }
+        if self.pipeline.is_empty() {
+            return Err(Error::InvalidOptions("the pipeline needs at least one codec".to_string()));
+        }
+        if self.fse && !self.pipeline.iter().any(|codec| matches!(codec, Codec::Huffman | Codec::Lz77Huffman)) {
+            return Err(Error::InvalidOptions(format!("FSE replaces Huffman stages, the pipeline {:?} has none", self.pipeline)));
+        }
+        Ok(())
+    }
+
+    fn header(&self, uncompressed_size: u64) -> Result<(Header, Option<Cipher>)> {
+        self.validate()?;
+        let mut header = Header::new(self.coded_pipeline(), self.lz_bits, self.huffman_bits, uncompressed_size);
+        if self.lz_window != 0 {
+            header.set_lz_window(self.lz_window);
+        }
+        header.set_code_length_limit(self.code_length_limit);
+        let cipher = match &self.password {
+            Some(password) if password.is_empty() => return Err(Error::Password("the password must not be empty")),
+            Some(password) => {
+                let (encryption, cipher) = Encryption::new(password)?;
+                header.set_encryption(encryption);
+                Some(cipher)
+            },
+            None => None,
+        };
+        Ok((header, cipher))
+    }
+
+    /// Writes an archive of the file or directory at `path` to `output`, one file at a time.
+    /// Returns the output and the summary with the size of the entry stream. The uncompressed size
+    /// in the header stays unknown, `write_archive_seekable` stores it.
+    pub fn write_archive<W: Write>(&self, path: &str, output: W) -> Result<(W, ArchiveSummary)> {
+        let (output, summary, _) = self.stream_archive(path, output)?;
+        Ok((output, summary))
+    }
+
+    /// Like `write_archive`, but rewinds `output` at the end to store the uncompressed size in the header.
+    pub fn write_archive_seekable<W: Write + Seek>(&self, path: &str, mut output: W) -> Result<(W, ArchiveSummary)> {
+        let start = output.stream_position().map_err(|e| Error::from_stream(e, "archive"))?;
+        let (mut output, summary, header) = self.stream_archive(path, output)?;
+        let header = Header { uncompressed_size: summary.size, ..header };
+        let rewrite = |output: &mut W| -> io::Result<()> {
+            let end = output.stream_position()?;
+            output.seek(SeekFrom::Start(start))?;
+            output.write_all(&header.serialize())?;
+            output.seek(SeekFrom::Start(end))?;
+            output.flush()
+        };
+        rewrite(&mut output).map_err(|e| Error::from_stream(e, "archive"))?;
+        Ok((output, summary))
+    }
+
+    /// Writes the archive and returns the output, the summary and the header used.
+    fn stream_archive<W: Write>(&self, path: &str, output: W) -> Result<(W, ArchiveSummary, Header)> {
+        let (header, cipher) = self.header(0)?;
+        let mut writer = FrameWriter::new(output, header.clone(), self.parser, cipher).map_err(|e| Error::from_stream(e, "archive"))?;
+        let (entries, skipped) = Archive::stream_from_disk(path, self.follow_links, &mut writer)?;
+        let (output, size) = writer.finish(entries).map_err(|e| Error::from_stream(e, "archive"))?;
+        Ok((output, ArchiveSummary { size, skipped }, header))
+    }
+
+    /// Returns a writer that compresses everything written to it into `output`.
+    pub fn encoder<W: Write>(&self, output: W) -> Result<Encoder<W>> {
+        let (header, cipher) = self.header(0)?;
+        Ok(Encoder(FrameWriter::new(output, header, self.parser, cipher).map_err(|e| Error::from_stream(e, "output"))?))
+    }
+
+    /// Compresses `data` into a new container.
+    pub fn encode(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let (header, cipher) = self.header(data.len() as u64)?;
+        let mut writer = FrameWriter::new(Vec::new(), header, self.parser, cipher).expect("writing into memory can not fail");
+        writer.write_all(data).expect("writing into memory can not fail");
+        Ok(writer.finish(Vec::new()).expect("writing into memory can not fail").0)
+    }
+}
+
+/// Compresses plain data, see `Options::encoder`. `finish` has to be called at the end.
+pub struct Encoder<W: Write>(FrameWriter<W>);
+
+impl<W: Write> Encoder<W> {
+    /// Writes the remaining data and returns the output.
+    pub fn finish(self) -> Result<W> {
+        Ok(self.0.finish(Vec::new()).map_err(|e| Error::from_stream(e, "output"))?.0)
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// Decompresses `data` written by `Options::encode` or `Encoder`.
+/// `password` is only needed for encrypted data.
+pub fn decode(data: &[u8], password: Option<&[u8]>) -> Result<Vec<u8>> {
+    let mut reader = ArchiveReader::new(data)?;
+    if let Some(password) = password.filter(|_| reader.is_encrypted()) {
+        reader.unlock(password)?;
+    }
+    let mut output = Vec::new();
+    reader.decoder()?.read_to_end(&mut output).map_err(|e| Error::from_stream(e, "archive"))?;
+    Ok(output)
+}
+
+/// Reads archives and compressed data written with `Options`.
+/// Encrypted input has to be unlocked with the password before anything else can be read.
+pub struct ArchiveReader<R: Read> {
+    input: R,
+    header: Header,
+    cipher: Option<Cipher>,
+}
+
+impl<R: Read> ArchiveReader<R> {
+    /// Reads the header at the start of `input`.
+    pub fn new(mut input: R) -> Result<Self> {
+        let header = Header::read(&mut input)?;
+        Ok(ArchiveReader { input, header, cipher: None })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.header.encryption.is_some()
+    }
+
+    /// Derives the key of an encrypted archive, fails for a wrong password.
+    pub fn unlock(&mut self, password: &[u8]) -> Result<()> {
+        let encryption = self.header.encryption.as_ref().ok_or(Error::Password("the archive is not encrypted"))?;
+        self.cipher = Some(encryption.cipher(password)?);
+        Ok(())
+    }
+
+    fn cipher(&self) -> Result<Option<Cipher>> {
+        match (self.is_encrypted(), &self.cipher) {
+            (true, None) => Err(Error::Password("the archive is encrypted, a password is needed")),
+            (_, cipher) => Ok(cipher.clone()),
+        }
+    }
+
+    /// The decompressed content, which is the entry stream for archives.
+    pub fn decoder(self) -> Result<FrameReader<R>> {
+        let cipher = self.cipher()?;
+        Ok(FrameReader::new(self.input, &self.header, cipher))
+    }
+
+    /// Extracts the selected entries into `directory`, see `Archive::stream_to_disk`.
+    pub fn extract(self, directory: &str, selection: &Selection, options: ExtractOptions) -> Result<ExtractSummary> {
+        Archive::stream_to_disk(self.decoder()?, directory, selection, options)
+    }
+
+    /// Iterates over the entries of an archive.
+    pub fn entries(self) -> Result<impl Iterator<Item = Result<Entry>>> {
+        Ok(EntryReader::new(self.decoder()?).entries())
+    }
+
+    /// Returns the paths of all files whose content does not match its checksum.
+    pub fn verify(self) -> Result<Vec<String>> {
+        Archive::verify_stream(self.decoder()?)
+    }
+
+    /// Writes the content of the selected files to `output`.
+    pub fn cat(self, selection: &Selection, output: &mut impl Write) -> Result<()> {
+        Archive::cat_stream(self.decoder()?, selection, output)
+    }
+}
+
+impl<R: Read + Seek> ArchiveReader<R> {
+    /// Opens the archive for random access through its index, `None` if it has none.
+    pub fn indexed(self) -> Result<Option<IndexedArchive<R>>> {
+        let cipher = self.cipher()?;
+        match self.header.flags & header::FLAG_INDEX {
+            0 => Ok(None),
+            _ => Ok(Some(IndexedArchive::open(self.input, &self.header, cipher)?)),
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use std::io::Write;
+use serde::Serialize;
+
+use crate::archive::Entry;
+use crate::error::{IoContext, Result};
+use crate::metadata::{Metadata, Timestamp};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ListFormat {
+    /// Permissions, owner, size, modification time and path
+    Long,
+    /// One path per line
+    Plain,
+    /// A JSON array with one object per entry
+    Json,
+}
+
+#[derive(Serialize)]
+struct JsonEntry<'a> {
+    path: &'a str,
+    kind: &'static str,
+    size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<&'a Metadata>,
+}
+
+/// Prints every entry to `output`.
+pub fn list(entries: impl IntoIterator<Item = Result<Entry>>, format: ListFormat, output: &mut impl Write) -> Result<()> {
+    let mut first = true;
+    if format == ListFormat::Json {
+        write!(output, "[").with_path("stdout")?;
+    }
+    for entry in entries {
+        let entry = entry?;
+        match format {
+            ListFormat::Plain => writeln!(output, "{}", entry.path()),
+            ListFormat::Long => writeln!(output, "{}", long_line(&entry)),
+            ListFormat::Json => {
+                let json = serde_json::to_string(&json_entry(&entry)).expect("entries are always valid JSON");
+                write!(output, "{}\n  {}", if first { "" } else { "," }, json)
+            },
+        }.with_path("stdout")?;
+        first = false;
+    }
+    if format == ListFormat::Json {
+        writeln!(output, "\n]").with_path("stdout")?;
+    }
+    Ok(())
+}
+
+fn json_entry(entry: &Entry) -> JsonEntry<'_> {
+    match entry {
+        Entry::Directory { path, metadata } => JsonEntry { path, kind: "directory", size: 0, target: None, metadata: Some(metadata) },
+        Entry::File { path, metadata, size } => JsonEntry { path, kind: "file", size: *size, target: None, metadata: Some(metadata) },
+        Entry::Symlink { path, metadata, target } => JsonEntry { path, kind: "symlink", size: 0, target: Some(target), metadata: Some(metadata) },
+        Entry::Hardlink { path, target } => JsonEntry { path, kind: "hardlink", size: 0, target: Some(target), metadata: None },
+    }
+}
+
+fn long_line(entry: &Entry) -> String {
+    let (kind, metadata, size) = match entry {
+        Entry::Directory { metadata, .. } => ('d', Some(metadata), 0),
+        Entry::File { metadata, size, .. } => ('-', Some(metadata), *size),
+        Entry::Symlink { metadata, .. } => ('l', Some(metadata), 0),
+        Entry::Hardlink { .. } => ('h', None, 0),
+    };
+    let columns = match metadata {
+        Some(metadata) => format!("{} {:<17} {:>12} {}",
+            mode_string(kind, metadata.mode),
+            owner(metadata),
+            size,
+            format_time(metadata.mtime)),
+        None => format!("{}--------- {:<17} {:>12} {:16}", kind, "", size, ""),
+    };
+    match entry {
+        Entry::Symlink { target, .. } => format!("{} {} -> {}", columns, entry.path(), target),
+        Entry::Hardlink { target, .. } => format!("{} {} link to {}", columns, entry.path(), target),
+        _ => format!("{} {}", columns, entry.path()),
+    }
+}
+
+fn owner(metadata: &Metadata) -> String {
+    let user = metadata.user.clone().unwrap_or_else(|| metadata.uid.to_string());
+    let group = metadata.group.clone().unwrap_or_else(|| metadata.gid.to_string());
+    format!("{}/{}", user, group)
+}
+
+fn mode_string(kind: char, mode: u32) -> String {
+    std::iter::once(kind)
+        .chain("rwxrwxrwx".chars().enumerate().map(|(i, c)| if mode & (1 << (8 - i)) != 0 { c } else { '-' }))
+        .collect()
+}
+
+/// Formats a timestamp as UTC `YYYY-MM-DD HH:MM`.
+fn format_time(time: Timestamp) -> String {
+    let days = time.secs.div_euclid(86400);
+    let seconds = time.secs.rem_euclid(86400);
+    // Converts days since the epoch into a civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02} {:02}:{:02}", year, month, day, seconds / 3600, seconds % 3600 / 60)
+}
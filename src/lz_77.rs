@@ -1,12 +1,34 @@
-use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use rayon::iter::{IndexedParallelIterator, IntoParallelIterator, ParallelIterator};
 use serde::{Deserialize, Serialize};
 use crate::bitbuffer::{self, BitBuffer};
+use crate::error::{Error, Result};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
 use suffix_array::SuffixArray;
-use indicatif::ParallelProgressIterator;
+
+/// How the input of a chunk is split into literals and matches. The decoder does not depend on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parser {
+    /// Greedy with the most recent candidate of a hash table, fast but misses many matches.
+    Fast,
+    /// Greedy with the longest previous match found through the suffix array.
+    Greedy,
+    /// Like `Greedy`, but a match is replaced by a literal if one of the next positions, up to the
+    /// given number of them (1 or 2), starts a sufficiently longer match.
+    Lazy(u8),
+    /// The parse with the smallest encoded size, from the same matches as `Greedy`. The token coders
+    /// estimate the size from the symbols of a first parse.
+    Optimal,
+}
+
+/// A factor of the parse: source position, length and literal. Literals have length zero.
+pub(crate) type Factor = (u32, u32, u8);
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct LZ77 {
     pub bitbuffers: Vec<bitbuffer::BitBuffer>,
+    /// CRC32C of every uncompressed chunk.
+    pub checksums: Vec<u32>,
 }
 
 impl LZ77 {
@@ -14,20 +36,83 @@ impl LZ77 {
         bincode::serialize(&self).unwrap()
     }
     
-    pub fn deserialize(input: &[u8]) -> Self {
-        bincode::deserialize(input).unwrap()
+    pub fn deserialize(input: &[u8]) -> Result<Self> {
+        let lz: Self = bincode::deserialize(input)?;
+        if lz.bitbuffers.len() != lz.checksums.len() {
+            return Err(Error::corrupt("LZ77 chunk count does not match its checksums"));
+        }
+        lz.bitbuffers.iter().enumerate().try_for_each(|(i, chunk)| chunk.validate().map_err(|e| e.at(&format!("LZ77 chunk {}", i))))?;
+        Ok(lz)
     }
 
+    /// Length of the common prefix of the suffixes at `i` and `j`, up to the end of the input.
     #[inline]
     fn lpc(input: &[u8], i: u32, j: u32) -> u32 {
-        input[i as usize..]
-            .iter()
-            .zip(input[j as usize..].iter())
-            .position(|(a,b)| a != b)
-            .unwrap_or(0) as u32
+        Self::match_length(input, i as usize, j as usize, usize::MAX)
+    }
+
+    /// Length of the common prefix of the suffixes at `i` and `j`, at most `limit`.
+    #[inline]
+    fn match_length(input: &[u8], i: usize, j: usize, limit: usize) -> u32 {
+        input[i..].iter().zip(&input[j..]).take(limit).take_while(|(a, b)| a == b).count() as u32
+    }
+
+    /// Encodes `input[start..]` as one chunk, matches may also refer to the history `input[..start]`.
+    pub fn encode_chunk(input: &[u8], start: usize, parser: Parser) -> BitBuffer {
+        let (factors, flag_mode) = Self::parse(input, start, parser);
+        Self::write_factors(input, start, factors, flag_mode)
+    }
+
+    /// Splits `input[start..]` into factors, the optimal parse also returns the flag mode it was made for.
+    pub(crate) fn parse(input: &[u8], start: usize, parser: Parser) -> (Vec<Factor>, Option<bool>) {
+        match parser {
+            Parser::Fast => (Self::hash_factors(input, start), None),
+            Parser::Greedy => (Self::greedy_factors(input, start), None),
+            Parser::Lazy(steps) => (Self::lazy_factors(input, start, steps.clamp(1, 2) as usize), None),
+            Parser::Optimal => {
+                let (factors, flag_mode) = Self::optimal_factors(input, start);
+                (factors, Some(flag_mode))
+            },
+        }
     }
 
     pub fn fast_encode(input: &[u8]) -> BitBuffer {
+        Self::encode_chunk(input, 0, Parser::Greedy)
+    }
+
+    /// Greedy parse looking up only the last position with the same four byte prefix.
+    fn hash_factors(input: &[u8], start: usize) -> Vec<Factor> {
+        const HASH_BITS: u32 = 16;
+        let hash = |prefix: &[u8]| (u32::from_le_bytes(prefix.try_into().unwrap()).wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize;
+        let mut table = vec![u32::MAX; 1 << HASH_BITS];
+        for (i, prefix) in input[..(start + 3).min(input.len())].windows(4).enumerate() {
+            table[hash(prefix)] = i as u32;
+        }
+        let mut factors = Vec::new();
+        let mut i = start;
+        while i < input.len() {
+            if let Some(prefix) = input.get(i..i + 4) {
+                let hash = hash(prefix);
+                let candidate = table[hash];
+                table[hash] = i as u32;
+                if candidate != u32::MAX {
+                    let l = Self::match_length(input, i, candidate as usize, usize::MAX);
+                    if l >= 4 {
+                        factors.push((candidate, l, 0));
+                        i += l as usize;
+                        continue;
+                    }
+                }
+            }
+            factors.push((0, 0, input[i]));
+            i += 1;
+        }
+        factors
+    }
+
+    /// For every position the two earlier suffixes next to it in the suffix array, the previous
+    /// and next smaller value. One of them shares the longest prefix with the suffix at the position.
+    fn candidates(input: &[u8]) -> Vec<(u32, u32)> {
         let n = input.len();
 
         let (_,suffix_array) = SuffixArray::new(input).into_parts();
@@ -59,19 +144,157 @@ impl LZ77 {
                 psv = psv.iter().map(|&i| if i == u32::MAX {0} else {i}).collect::<Vec<_>>();
             });
         }); 
-        
+
+        (0..n).map(|k| {
+            let rank = inverse_suffix_array[k];
+            (suffix_array[psv[rank] as usize], suffix_array[nsv[rank] as usize])
+        }).collect()
+    }
+
+    fn greedy_factors(input: &[u8], start: usize) -> Vec<Factor> {
+        let candidates = Self::candidates(input);
         let mut factors = Vec::new();
-        let mut k = 0u32;
-        while k < n as u32{
-            let psv = suffix_array[psv[inverse_suffix_array[k as usize] as usize] as usize];
-            let nsv = suffix_array[nsv[inverse_suffix_array[k as usize] as usize] as usize];
+        let mut k = start as u32;
+        while k < input.len() as u32 {
+            let (psv, nsv) = candidates[k as usize];
             let (p,l,c,indx) = LZ77::lz_factor(k, psv, nsv, input);
             k = indx;
             factors.push((p,l,c));
         } 
-        
+        factors
+    }
+
+    /// Greedy parse that first checks whether starting `s` bytes later, for `s` up to `steps`,
+    /// gives a match more than `s` bytes longer. Then the current byte is written as a literal.
+    fn lazy_factors(input: &[u8], start: usize, steps: usize) -> Vec<Factor> {
+        let candidates = Self::candidates(input);
+        let longest = |k: usize| {
+            let (psv, nsv) = candidates[k];
+            let (v1, v2) = (Self::match_length(input, k, psv as usize, usize::MAX), Self::match_length(input, k, nsv as usize, usize::MAX));
+            match k {
+                0 => (0, 0),
+                _ if v1 > v2 => (psv, v1),
+                _ => (nsv, v2),
+            }
+        };
+        let mut factors = Vec::new();
+        let mut k = start;
+        while k < input.len() {
+            let (p, l) = longest(k);
+            let deferred = l > 0 && (1..=steps)
+                .take_while(|s| k + s < input.len())
+                .any(|s| longest(k + s).1 > l + s as u32);
+            if l == 0 || deferred {
+                factors.push((0, 0, input[k]));
+                k += 1;
+            } else {
+                factors.push((p, l, 0));
+                k += l as usize;
+            }
+        }
+        factors
+    }
+
+    /// Number of bits for the length of a factor that starts `index` bytes into the chunk.
+    #[inline]
+    fn lenght_size_at(index: usize) -> u8 {
+        Self::lenght_size(31 - (index.max(1) as u32).leading_zeros() as u8)
+    }
+
+    /// The parse with the fewest bits under the encoding of `write_factors`, and its flag mode.
+    ///
+    /// The cost of a factor only depends on where it starts, so this is a shortest path over the
+    /// positions of the chunk: from every position there is a literal edge and one edge of the same
+    /// cost to every position the longest match reaches, see `shortest_parse`. Both flag modes are tried
+    /// and the cheaper one is used.
+    fn optimal_factors(input: &[u8], start: usize) -> (Vec<Factor>, bool) {
+        let matches = Self::optimal_matches(input, start, |i| (1 << Self::lenght_size_at(i)) - 1);
+        let parse = |flag_mode: bool| Self::shortest_parse(input, start, &matches, 1,
+            |i| if flag_mode { 1 } else { Self::lenght_size_at(i) as u64 } + 8,
+            |i, _| flag_mode as u64 + Self::lenght_size_at(i) as u64 + (32 - (i as u32).leading_zeros()) as u64,
+        );
+        let ((flagged, flagged_factors), (plain, plain_factors)) = rayon::join(|| parse(true), || parse(false));
+        match flagged <= plain {
+            true => (flagged_factors, true),
+            false => (plain_factors, false),
+        }
+    }
+
+    /// The matches with the previous and the next smaller suffix at every position after `start`, cut to
+    /// `limit(i)`, the maximum length the encoding allows there. The longer one comes first.
+    pub(crate) fn optimal_matches(input: &[u8], start: usize, limit: impl Fn(usize) -> usize) -> Vec<[(u32, u32); 2]> {
+        let candidates = Self::candidates(input);
+        // A match from p of length l at the previous position continues from p + 1 with l - 1 bytes known
+        // to be equal, which keeps long repetitions from being compared over and over.
+        let mut previous = [(0u32, 0u32); 2];
+        candidates.iter().enumerate().skip(start).map(|(i, &(psv, nsv))| {
+            let limit = limit(i);
+            let length = |j: u32| {
+                let known = previous.iter().find(|&&(p, l)| l > 1 && p + 1 == j).map_or(0, |&(_, l)| l as usize - 1).min(limit);
+                known as u32 + Self::match_length(input, i + known, j as usize + known, limit - known)
+            };
+            let (v1, v2) = (length(psv), length(nsv));
+            previous = match i {
+                0 => [(0, 0); 2],
+                _ if v1 > v2 => [(psv, v1), (nsv, v2)],
+                _ => [(nsv, v2), (psv, v1)],
+            };
+            previous
+        }).collect()
+    }
+
+    /// Finds the parse of `input[start..]` with the lowest cost, using the first `candidates` matches of
+    /// `optimal_matches` or a literal at every position. `literal(i)` gives the bits of a literal at position `i`
+    /// and `matched(i, p)` those of a match from `p` there, which may not depend on its length. `matched`
+    /// is only called for actual matches, `p < i`.
+    pub(crate) fn shortest_parse(input: &[u8], start: usize, matches: &[[(u32, u32); 2]], candidates: usize, literal: impl Fn(usize) -> u64, matched: impl Fn(usize, u32) -> u64) -> (u64, Vec<Factor>) {
+        let n = input.len() - start;
+        // total[j] is the size of the best parse of the first j bytes after `start`, from[j] where its
+        // last factor starts and its source, `u32::MAX` if that is a literal.
+        let mut total = vec![0u64; n + 1];
+        let mut from = vec![(0u32, 0u32); n + 1];
+        // Matches that may still end at the current position, cheapest first.
+        let mut edges = BinaryHeap::new();
+        for j in 1..=n {
+            let i = j - 1;
+            for &(p, l) in matches[i][..candidates].iter().filter(|&&(_, l)| l > 0) {
+                debug_assert!((p as usize) < start + i);
+                edges.push(Reverse((total[i] + matched(start + i, p), i + l as usize, i as u32, p)));
+            }
+            let literal = total[i] + literal(start + i);
+            (total[j], from[j]) = (literal, (u32::MAX, 0));
+            while edges.peek().is_some_and(|&Reverse((_, reach, _, _))| reach < j) {
+                edges.pop();
+            }
+            if let Some(&Reverse((edge, _, start, p))) = edges.peek() {
+                if edge < literal {
+                    (total[j], from[j]) = (edge, (start, p));
+                }
+            }
+        }
+        let mut factors = Vec::new();
+        let mut j = n;
+        while j > 0 {
+            j = match from[j] {
+                (u32::MAX, _) => {
+                    factors.push((0, 0, input[start + j - 1]));
+                    j - 1
+                },
+                (i, p) => {
+                    factors.push((p, (j - i as usize) as u32, 0));
+                    i as usize
+                },
+            };
+        }
+        factors.reverse();
+        (total[n], factors)
+    }
+
+    /// Writes the factors of a chunk, matches of up to three bytes are written as literals where that is shorter.
+    /// Without a given flag mode it is chosen by the share of literals.
+    fn write_factors(input: &[u8], start: usize, factors: Vec<Factor>, flag_mode: Option<bool>) -> BitBuffer {
         let factors = factors.into_iter()
-            .scan(0, |count, (p,l,c)| {
+            .scan(start as u32, |count, (p,l,c)| {
                 *count += l.max(1);
                 if l == 1 && *count >= 128 {
                     return Some(vec![(0,0,input[p as usize])]);
@@ -87,15 +310,15 @@ impl LZ77 {
             .flatten()
             .collect::<Vec<_>>();
             
-        let mut current_char_index = 0usize;
-        let mut lenght_size = 1;
+        let mut current_char_index = start;
+        let mut lenght_size = Self::lenght_size_at(start);
         let mut max_lenght = 2u32.pow(lenght_size as u32) - 1;
         let mut buffer = BitBuffer::new();
 
         let char_count = factors.iter().filter(|(_,l,_)| *l == 0).count();
         let char_prob = char_count as f32 / factors.len() as f32;
         
-        let flag_mode = if char_prob > 0.25 {
+        let flag_mode = if flag_mode.unwrap_or(char_prob > 0.25) {
             buffer.write_bit(true);
             true
         } else {
@@ -122,7 +345,7 @@ impl LZ77 {
                     acc.write_bit(true);
                 } 
                 acc.write_bits(u32::MAX, lenght_size);
-                acc.write_bits(p as u32, current_bits);
+                acc.write_bits(p, current_bits);
                 p += max_lenght;
                 l -= max_lenght;
                 current_char_index += max_lenght as usize;
@@ -133,8 +356,8 @@ impl LZ77 {
             if flag_mode {
                 acc.write_bit(true);
             }
-            acc.write_bits(l as u32, lenght_size);
-            acc.write_bits(p as u32, current_bits);
+            acc.write_bits(l, lenght_size);
+            acc.write_bits(p, current_bits);
             current_char_index += l as usize;
             lenght_size = Self::lenght_size(31 - (current_char_index as u32).leading_zeros() as u8);
             max_lenght = 2u32.pow(lenght_size as u32) - 1;
@@ -145,18 +368,20 @@ impl LZ77 {
 
     #[inline]
     fn lenght_size(bits: u8) -> u8 {
-        (bits / 2).min(8).max(1)
+        (bits / 2).clamp(1, 8)
     }
 
-    fn decode_chunk(factors: Vec<(u32, u32, u8)>) -> Vec<u8> {
-        factors.into_iter().fold(Vec::new(), |mut acc, (p,l,c)| {
+    /// Appends the decoded chunk to `acc`, which holds the history the chunk was encoded with.
+    fn decode_chunk(factors: Vec<(u32, u32, u8)>, acc: &mut Vec<u8>) -> Result<()> {
+        factors.into_iter().try_for_each(|(p,l,c)| {
             match l {
                 0 => acc.push(c),
+                _ if p as usize >= acc.len() => return Err(Error::corrupt("LZ77 reference points past the decoded data")),
                 _ => for i in 0..l {
                     acc.push(acc[p as usize + i as usize]);
                 },
             }
-            acc
+            Ok(())
         })
     }
 
@@ -175,71 +400,134 @@ impl LZ77 {
         (p, l, 0, i + l)
     }
 
-    pub fn encode(input: &[u8], bits: u8) -> LZ77 {
-        let n = input.len();
+    pub fn encode(input: &[u8], bits: u8, parser: Parser) -> LZ77 {
+        Self::encode_with_history(input, 0, bits, parser, 0)
+    }
+
+    /// Encodes `input[start..]` in independent chunks of `2^bits - 2` bytes, except that every chunk
+    /// may refer to up to `window` bytes before it, including the history `input[..start]`.
+    /// Chunks are still encoded in parallel, only decoding has to resolve them in order.
+    pub fn encode_with_history(input: &[u8], start: usize, bits: u8, parser: Parser, window: usize) -> LZ77 {
+        let (bitbuffers, checksums) = Self::map_chunks(input, start, bits, window, |chunk, start| LZ77::encode_chunk(chunk, start, parser))
+            .into_iter()
+            .unzip();
+        LZ77 {
+            bitbuffers,
+            checksums,
+        }
+    }
+
+    /// Splits `input[start..]` into chunks of `2^bits - 2` bytes and calls `encode` in parallel with
+    /// every chunk preceded by up to `window` bytes of history and the length of that history.
+    /// Returns the results together with the CRC32C of each chunk.
+    pub(crate) fn map_chunks<T: Send>(input: &[u8], start: usize, bits: u8, window: usize, encode: impl Fn(&[u8], usize) -> T + Sync) -> Vec<(T, u32)> {
+        let n = input.len() - start;
         let chunk_size = 2usize.pow(bits as u32) - 2;
-        let num_chunks = n / chunk_size + if n % chunk_size == 0 {0} else {1};
+        let num_chunks = n / chunk_size + if n.is_multiple_of(chunk_size) {0} else {1};
 
-        let progress = indicatif::ProgressBar::new(num_chunks as u64);
-        progress.set_position(0);
-        let data = (0..num_chunks).into_par_iter() 
+        (0..num_chunks).into_par_iter() 
             .map(|i| {
-                let start = i * chunk_size;
-                let end = usize::min((i + 1) * chunk_size, n);
-                let chunk = &input[start..end];
-                let factors = LZ77::fast_encode(chunk);
-                progress.inc(1);
-                factors
+                let begin = start + i * chunk_size;
+                let end = usize::min(begin + chunk_size, input.len());
+                let history = begin.saturating_sub(window);
+                (encode(&input[history..end], begin - history), crc32c::crc32c(&input[begin..end]))
             })
-            .collect::<Vec<_>>();
-
-        progress.finish_and_clear();
-        
-        LZ77 {
-            bitbuffers: data,
-        }
+            .collect::<Vec<_>>()
     }
 
-    pub fn decode(self) -> Vec<u8> {
-        self.bitbuffers.into_par_iter().progress().flat_map(|mut chunk| {
-            let mut current_char_index = 0usize;
-            let mut factors = Vec::new();
-            let mut current_bits;
-            let mut lenght_size = 1;
-            let flag_mode = chunk.read_bit().unwrap();
-            if flag_mode {
-                while let Some(char_flag) = chunk.read_bit() {
-                    match char_flag {
-                        false => {
-                            factors.push((0, 0, chunk.read_byte().unwrap()));
-                            current_char_index += 1;
-                            lenght_size = Self::lenght_size(31 - (current_char_index as u32).leading_zeros() as u8);
-                        },
-                        true => {
-                            let l = chunk.read_bits(lenght_size).unwrap();
-                            current_bits =  32 - (current_char_index as u32).leading_zeros() as u8;
-                            factors.push((chunk.read_bits(current_bits).unwrap(), l, 0));         
-                            current_char_index += l as usize;     
-                            lenght_size = Self::lenght_size(31 - (current_char_index as u32).leading_zeros() as u8);
-                        },
-                    }
-                }
-            } else {
-                while let Some(l) = chunk.read_bits(lenght_size) {
-                    if l == 0 {
-                        factors.push((0, 0, chunk.read_byte().unwrap()));
+    /// Reads the factors of a chunk that was encoded after `start` bytes of history.
+    fn read_factors(mut chunk: BitBuffer, start: usize) -> Result<Vec<(u32, u32, u8)>> {
+        let mut current_char_index = start;
+        let mut factors = Vec::new();
+        let mut current_bits;
+        let mut lenght_size = Self::lenght_size_at(start);
+        let flag_mode = chunk.read_bit()?;
+        if flag_mode {
+            while chunk.remaining() > 0 {
+                match chunk.read_bit()? {
+                    false => {
+                        factors.push((0, 0, chunk.read_byte()?));
                         current_char_index += 1;
                         lenght_size = Self::lenght_size(31 - (current_char_index as u32).leading_zeros() as u8);
-                    } else {
+                    },
+                    true => {
+                        let l = chunk.read_bits(lenght_size)?;
                         current_bits =  32 - (current_char_index as u32).leading_zeros() as u8;
-                        factors.push((chunk.read_bits(current_bits).unwrap(), l, 0));         
+                        factors.push((chunk.read_bits(current_bits)?, l, 0));         
                         current_char_index += l as usize;     
                         lenght_size = Self::lenght_size(31 - (current_char_index as u32).leading_zeros() as u8);
-                    }
+                    },
+                }
+            }
+        } else {
+            while chunk.remaining() >= lenght_size as usize {
+                let l = chunk.read_bits(lenght_size)?;
+                if l == 0 {
+                    factors.push((0, 0, chunk.read_byte()?));
+                    current_char_index += 1;
+                    lenght_size = Self::lenght_size(31 - (current_char_index as u32).leading_zeros() as u8);
+                } else {
+                    current_bits =  32 - (current_char_index as u32).leading_zeros() as u8;
+                    factors.push((chunk.read_bits(current_bits)?, l, 0));         
+                    current_char_index += l as usize;     
+                    lenght_size = Self::lenght_size(31 - (current_char_index as u32).leading_zeros() as u8);
                 }
             }
-            LZ77::decode_chunk(factors)
-        }).collect::<Vec<_>>()
+        }
+        Ok(factors)
+    }
+
+    pub fn decode(self) -> Result<Vec<u8>> {
+        self.read(0, 0, 0)?.resolve(&[])
+    }
+
+    /// Reads the factors of all chunks in parallel. The arguments have to match `encode_with_history`:
+    /// `history` bytes came before the first chunk, chunks hold `chunk_size` bytes and refer to up to `window` bytes before them.
+    pub fn read(self, history: usize, window: usize, chunk_size: usize) -> Result<Factors> {
+        let chunks = self.bitbuffers.into_par_iter().zip(self.checksums).enumerate().map(|(i, (chunk, checksum))| {
+            let start = window.min(history + i * chunk_size);
+            let factors = LZ77::read_factors(chunk, start).map_err(|e| e.at(&format!("LZ77 chunk {}", i)))?;
+            Ok(ParsedChunk { start, factors, checksum })
+        }).collect::<Result<Vec<_>>>()?;
+        Ok(Factors(chunks))
+    }
+}
+
+pub(crate) struct ParsedChunk {
+    /// Length of the history the chunk was encoded with.
+    pub start: usize,
+    pub factors: Vec<Factor>,
+    pub checksum: u32,
+}
+
+/// The chunks of an LZ77 stream read back into factors, see `LZ77::read`.
+pub struct Factors(pub(crate) Vec<ParsedChunk>);
+
+impl Factors {
+    /// Decodes the chunks, `history` has to end with the data that came before the first chunk.
+    /// Chunks without history are decoded in parallel, the others one after another.
+    pub fn resolve(self, history: &[u8]) -> Result<Vec<u8>> {
+        let decode = |i: usize, chunk: ParsedChunk, mut acc: Vec<u8>| {
+            let location = format!("LZ77 chunk {}", i);
+            LZ77::decode_chunk(chunk.factors, &mut acc).map_err(|e| e.at(&location))?;
+            match crc32c::crc32c(&acc[chunk.start..]) == chunk.checksum {
+                true => Ok(acc.split_off(chunk.start)),
+                false => Err(Error::Checksum(location)),
+            }
+        };
+        if self.0.iter().all(|chunk| chunk.start == 0) {
+            let chunks = self.0.into_par_iter().enumerate()
+                .map(|(i, chunk)| decode(i, chunk, Vec::new()))
+                .collect::<Result<Vec<_>>>()?;
+            return Ok(chunks.concat());
+        }
+        let mut output = history.to_vec();
+        for (i, chunk) in self.0.into_iter().enumerate() {
+            let begin = output.len().checked_sub(chunk.start).ok_or_else(|| Error::corrupt("LZ77 history is missing"))?;
+            let decoded = decode(i, chunk, output[begin..].to_vec())?;
+            output.extend_from_slice(&decoded);
+        }
+        Ok(output.split_off(history.len()))
     }
 
 }
\ No newline at end of file
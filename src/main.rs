@@ -1,83 +1,252 @@
 use std::fs;
+use std::io::{self, BufReader, BufWriter, Read, Seek};
 use clap::Parser;
+use indicatif::{ProgressBar, ProgressFinish, ProgressStyle};
 
-mod archive;
-mod lz_77;
-mod huffman;
-mod bitbuffer;
-mod terminal_interface;
+use tmy::archive::Archive;
+use tmy::error::IoContext;
+use tmy::fse::ParallelFse;
+use tmy::header::FLAG_INDEX;
+use tmy::huffman::ParrallelHuffman;
+use tmy::listing;
+use tmy::lz_77::{self, LZ77};
+use tmy::{ArchiveReader, ArchiveSummary, Error, ExtractOptions, IndexedArchive, Options, Result, Selection};
 
-use huffman::ParrallelHuffman;
-use archive::Archive;
-use lz_77::LZ77;
+mod terminal_interface;
 
 fn main() {
     let args = terminal_interface::Args::parse();
 
-    let lz_buffer_size = args.lz_buffer as u8;
-    let huffman_bits = args.huffman_buffer as u8;
+    let lz_buffer_size = args.lz_buffer.unwrap_or(tmy::DEFAULT_LZ_BITS);
+    let huffman_bits = args.huffman_buffer.unwrap_or(tmy::DEFAULT_HUFFMAN_BITS);
 
-    if let Some(path) = args.encrypt {
-        compress(&path, lz_buffer_size, huffman_bits);
+    let options = args.options();
+    let key = Key { prompt: args.password, key_file: args.key_file.as_deref() };
+
+    let result = if let Some(path) = args.encrypt {
+        compress(&path, args.output.as_deref(), &key, options)
     } else if let Some(path) = args.decrypt {
-        decompress(&path);
+        decompress(&path, &args.directory, &key, &Selection::new(&args.patterns), ExtractOptions { on_conflict: args.on_conflict.into(), allow_unsafe: args.allow_unsafe })
+    } else if let Some(path) = args.cat {
+        cat(&path, &key, &Selection::new(&args.patterns))
+    } else if let Some(path) = args.list {
+        list(&path, &key, args.format.into())
+    } else if let Some(path) = args.verify {
+        verify(&path, &key)
+    } else if let Some(path) = args.info {
+        info(&path)
     } else if let Some(path) = args.benchmark{
-        benchmark(&path, lz_buffer_size, huffman_bits);
+        benchmark(&path, lz_buffer_size, huffman_bits, args.follow_links)
+    } else {
+        Ok(())
+    };
+
+    if let Err(error) = result {
+        eprintln!("Error: {}", error);
+        std::process::exit(1);
     }
 }
 
-fn compress(path: &str, lz_buffer_size: u8, huffman_bits: u8) {
-    let root = Archive::read_from_disk(&path);
-    let serialized = root.serialize();
-    if serialized.len() >= 2usize.pow(20) {
-        println!("Read archive of size {}MB", serialized.len() / 2usize.pow(20));
+/// Path that stands for stdin or stdout.
+const STDIO: &str = "-";
+
+fn format_size(message: &str, size: u64) -> String {
+    if size >= 2u64.pow(20) {
+        format!("{} {}MB", message, size / 2u64.pow(20))
     } else {
-        println!("Read archive of size {}KB", serialized.len() / 2usize.pow(10));
+        format!("{} {}KB", message, size / 2u64.pow(10))
     }
-    let mut lz_encoded = LZ77::encode(&serialized, lz_buffer_size).serialize();
-    let mut huffman = ParrallelHuffman::encrypt(&lz_encoded, huffman_bits).serialize();
-    let full_path = fs::canonicalize(path).unwrap();
-    let dir_name = full_path.file_name().unwrap().to_str().unwrap();
+}
 
-    let compressed = if lz_encoded.len() <= huffman.len() {
-        lz_encoded.insert(0, 0);
-        lz_encoded
-    } else {
-        huffman.insert(0, 1);
-        huffman
+fn print_size(message: &str, size: u64) {
+    println!("{}", format_size(message, size));
+}
+
+/// A spinner on stderr with the archive bytes read or written so far, cleared when it is dropped.
+fn progress_bar() -> ProgressBar {
+    let progress = ProgressBar::new_spinner().with_finish(ProgressFinish::AndClear);
+    progress.set_style(ProgressStyle::with_template("{spinner} {bytes} ({bytes_per_sec})").unwrap());
+    progress
+}
+
+/// Compresses `path` into the archive `output`, `<dirname>.tmy` in the current directory by default.
+/// The archive is encrypted if a key is given.
+fn compress(path: &str, output: Option<&str>, key: &Key, mut options: Options) -> Result<()> {
+    let dir_name = fs::canonicalize(path).with_path(path)?
+        .file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| name.to_string())
+        .ok_or_else(|| Error::InvalidName(path.to_string()))?;
+    let output = output.map(|output| output.to_string()).unwrap_or_else(|| format!("{}.tmy", dir_name));
+    options.validate()?;
+    if key.is_set() {
+        options = options.password(key.password(true)?);
+    }
+
+    if output == STDIO {
+        // stdout can not be rewound, so the size in the header stays unknown.
+        // Messages go to stderr to keep the archive intact.
+        let (_, summary) = options.write_archive(path, progress_bar().wrap_write(BufWriter::new(io::stdout().lock())))?;
+        print_skipped(&summary);
+        eprintln!("{}", format_size("Read archive of size", summary.size));
+        return Ok(());
+    }
+
+    let file = progress_bar().wrap_write(BufWriter::new(fs::File::create(&output).with_path(&output)?));
+    let (mut file, summary) = options.write_archive_seekable(path, file)?;
+    file.progress.finish_and_clear();
+    print_skipped(&summary);
+    print_size("Read archive of size", summary.size);
+    print_size("Compressed archive to", file.stream_position().with_path(&output)?);
+    Ok(())
+}
+
+/// Warns about the special files left out of an archive, on stderr like the errors.
+fn print_skipped(summary: &ArchiveSummary) {
+    for path in &summary.skipped {
+        eprintln!("Skipping {}, it is not a file, directory or symbolic link", path);
+    }
+}
+
+/// Where the password of an encrypted archive comes from.
+struct Key<'a> {
+    /// Asks for a password when compressing, reading encrypted archives always asks if there is no key file.
+    prompt: bool,
+    key_file: Option<&'a str>,
+}
+
+impl Key<'_> {
+    fn is_set(&self) -> bool {
+        self.prompt || self.key_file.is_some()
+    }
+
+    /// Reads the key file or asks for the password on the terminal, twice if `confirm` is set.
+    fn password(&self, confirm: bool) -> Result<Vec<u8>> {
+        let password = match self.key_file {
+            Some(path) => fs::read(path).with_path(path)?,
+            None => {
+                let password = rpassword::prompt_password("Password: ").with_path("terminal")?;
+                if confirm && rpassword::prompt_password("Repeat password: ").with_path("terminal")? != password {
+                    return Err(Error::Password("passwords do not match"));
+                }
+                password.into_bytes()
+            },
+        };
+        match password.is_empty() {
+            true => Err(Error::Password("the password must not be empty")),
+            false => Ok(password),
+        }
+    }
+
+    /// Asks for the password if the archive is encrypted.
+    fn unlock<R: Read>(&self, reader: &mut ArchiveReader<R>) -> Result<()> {
+        match reader.is_encrypted() {
+            true => reader.unlock(&self.password(false)?),
+            false => Ok(()),
+        }
+    }
+}
+
+fn read_header(path: &str) -> Result<ArchiveReader<Box<dyn Read>>> {
+    let input: Box<dyn Read> = match path {
+        STDIO => Box::new(progress_bar().wrap_read(BufReader::new(io::stdin().lock()))),
+        path => Box::new(progress_bar().wrap_read(BufReader::new(fs::File::open(path).with_path(path)?))),
     };
-    if compressed.len() >= 2usize.pow(20) {
-        println!("Compressed archive to {}MB.", compressed.len() / 2usize.pow(20));
-    } else {
-        println!("Compressed archive to {}KB.", compressed.len() / 2usize.pow(10));
+    ArchiveReader::new(input)
+}
+
+/// Opens an archive for reading front to back, `-` reads it from stdin.
+fn open_archive(path: &str, key: &Key) -> Result<ArchiveReader<Box<dyn Read>>> {
+    let mut reader = read_header(path)?;
+    key.unlock(&mut reader)?;
+    Ok(reader)
+}
+
+/// Opens an archive for random access, `None` if it has no index or is read from stdin.
+fn open_indexed(path: &str, key: &Key) -> Result<Option<IndexedArchive<BufReader<fs::File>>>> {
+    if path == STDIO {
+        return Ok(None);
     }
-    fs::write(format!("{}.tmy",dir_name), compressed).unwrap();
+    let mut reader = ArchiveReader::new(BufReader::new(fs::File::open(path).with_path(path)?))?;
+    if reader.header().flags & FLAG_INDEX == 0 {
+        return Ok(None);
+    }
+    key.unlock(&mut reader)?;
+    reader.indexed()
 }
 
-fn decompress(path: &str) {
-    let contents = fs::read(path).unwrap();
-    if contents.len() < 2usize.pow(20) {
-        println!("Read archive of size {}KB", contents.len() / 2usize.pow(10));
-    } else {
-        println!("Read archive of size {}MB", contents.len() / 2usize.pow(20));
+/// Extracts the archive at `path` into `directory`, which is created if missing.
+fn decompress(path: &str, directory: &str, key: &Key, selection: &Selection, options: ExtractOptions) -> Result<()> {
+    if path != STDIO {
+        let size = fs::metadata(path).with_path(path)?.len();
+        print_size("Read archive of size", size);
     }
-    let root = if contents[0] == 0 {
-        let lz_encoded = &contents[1..];
-        let lz_encoded = LZ77::deserialize(&lz_encoded);
-        Archive::deserialize(&lz_encoded.decode())
-    } else {
-        let huffman_serialized = &contents[1..];
-        let huffman = ParrallelHuffman::deserialize(&huffman_serialized);
-        let lz_encoded = LZ77::deserialize(&huffman.decrypt());
-        Archive::deserialize(&lz_encoded.decode())
+    fs::create_dir_all(directory).with_path(directory)?;
+    // Extracting everything is fastest front to back, for a selection the index avoids decoding the rest.
+    let indexed = match selection.is_empty() {
+        true => None,
+        false => open_indexed(path, key)?,
+    };
+    let summary = match indexed {
+        Some(archive) => Archive::stream_to_disk(archive.select(selection), directory, selection, options)?,
+        None => open_archive(path, key)?.extract(directory, selection, options)?,
     };
-    root.write_to_disk(".");
+    for event in &summary.events {
+        println!("{}", event);
+    }
+    println!("{}", summary);
     println!("Decompressed archive successfully!");
+    Ok(())
+}
+
+fn cat(path: &str, key: &Key, selection: &Selection) -> Result<()> {
+    let output = &mut std::io::stdout().lock();
+    match open_indexed(path, key)? {
+        Some(archive) => Archive::cat_stream(archive.select_contents(selection), selection, output),
+        None => open_archive(path, key)?.cat(selection, output),
+    }
+}
+
+fn list(path: &str, key: &Key, format: listing::ListFormat) -> Result<()> {
+    let output = &mut std::io::stdout().lock();
+    match open_indexed(path, key)? {
+        Some(archive) => listing::list(archive.index().entries.iter().map(|entry| Ok(entry.entry.clone())), format, output),
+        None => listing::list(open_archive(path, key)?.entries()?, format, output),
+    }
+}
+
+fn verify(path: &str, key: &Key) -> Result<()> {
+    let damaged = open_archive(path, key)?.verify()?;
+    if !damaged.is_empty() {
+        for file in &damaged {
+            println!("Damaged file: {}", file);
+        }
+        return Err(Error::Checksum(format!("{} file(s)", damaged.len())));
+    }
+    println!("Archive is intact.");
+    Ok(())
+}
+
+/// Prints the header, the index is only read if the archive is not encrypted.
+fn info(path: &str) -> Result<()> {
+    let header = read_header(path)?.header().clone();
+    println!("{}", header);
+    if path != STDIO {
+        println!("Compressed size   : {} bytes", fs::metadata(path).with_path(path)?.len());
+    }
+    if header.encryption.is_none() {
+        if let Some(archive) = open_indexed(path, &Key { prompt: false, key_file: None })? {
+            let index = archive.index();
+            println!("Index             : {} entries in {} frames", index.entries.len(), index.frames.len());
+        }
+    }
+    Ok(())
 }
 
-fn benchmark(path: &str, lz_buffer_size: u8, huffman_bits: u8) {
-    println!("Starting benchmark with LZ77 chunk size {:2}MB and huffman chunk size {}KB", 2f32.powi(lz_buffer_size as i32 - 20), 2u32.pow(huffman_bits as u32 - 10));
-    let root = Archive::read_from_disk(&path);
+fn benchmark(path: &str, lz_buffer_size: u8, huffman_bits: u8, follow_links: bool) -> Result<()> {
+    Options::new().lz_bits(lz_buffer_size).huffman_bits(huffman_bits).validate()?;
+    println!("Starting benchmark with LZ77 chunk size {:2}MB and huffman chunk size {}KB", 2f32.powi(lz_buffer_size as i32 - 20), 2f32.powi(huffman_bits as i32 - 10));
+    let root = Archive::read_from_disk(path, follow_links)?;
     let serialized = root.serialize();
     if serialized.len() >= 2usize.pow(20) {
         println!("Read archive of size {}MB", serialized.len() / 2usize.pow(20));
@@ -89,11 +258,13 @@ fn benchmark(path: &str, lz_buffer_size: u8, huffman_bits: u8) {
     
     let start = std::time::Instant::now();
 
-    let lz_encoded = LZ77::encode(&serialized, lz_buffer_size).serialize();
+    let lz_encoded = LZ77::encode(&serialized, lz_buffer_size, lz_77::Parser::Greedy).serialize();
     let lz_time = std::time::Instant::now();
 
-    let huffman = ParrallelHuffman::encrypt(&lz_encoded, huffman_bits).serialize();
+    let huffman = ParrallelHuffman::encrypt(&lz_encoded, huffman_bits, tmy::DEFAULT_CODE_LENGTH_LIMIT).serialize();
     let lz_huffman_time = std::time::Instant::now();
+    let fse = ParallelFse::encode(&lz_encoded, huffman_bits).serialize();
+    let lz_fse_time = std::time::Instant::now();
 
     let compressed = if lz_encoded.len() <= huffman.len() {
         println!("Compression mode: LZ77 only.");
@@ -112,18 +283,36 @@ fn benchmark(path: &str, lz_buffer_size: u8, huffman_bits: u8) {
     println!("Testing Decompression...");
     let start_decompress = std::time::Instant::now();
     
-    let lz = ParrallelHuffman::decrypt(&ParrallelHuffman::deserialize(&huffman));
+    let lz = ParrallelHuffman::decrypt(&ParrallelHuffman::deserialize(&huffman)?, tmy::DEFAULT_CODE_LENGTH_LIMIT)?;
     let huffman_time_decode = std::time::Instant::now();
-    let decoded = LZ77::deserialize(&lz).decode();
+    let decoded = LZ77::deserialize(&lz)?.decode()?;
     let lz_time_decode = std::time::Instant::now();
+    let lz_bitwise = ParrallelHuffman::decrypt_bitwise(&ParrallelHuffman::deserialize(&huffman)?, tmy::DEFAULT_CODE_LENGTH_LIMIT)?;
+    let huffman_bitwise_time_decode = std::time::Instant::now();
+    let lz_fse = ParallelFse::deserialize(&fse)?.decode(huffman_bits)?;
+    let fse_time_decode = std::time::Instant::now();
 
     assert_eq!(lz, lz_encoded, "Decoded LZ77 does not match original LZ77");
-    assert_eq!(root, Archive::deserialize(&decoded), "Decoded archive does not match original"); 
+    assert_eq!(lz_bitwise, lz_encoded, "Bitwise decoded LZ77 does not match original LZ77");
+    assert_eq!(lz_fse, lz_encoded, "FSE decoded LZ77 does not match original LZ77");
+    assert_eq!(root, Archive::deserialize(&decoded)?, "Decoded archive does not match original"); 
 
     println!("Benchmark finished successfully!");
-    println!("LZ77    Compression      : {:?}", lz_time.duration_since(start));
-    println!("Huffman Compression      : {:?}", lz_huffman_time.duration_since(lz_time));
-    println!("Huffman Decompression    : {:?}", huffman_time_decode.duration_since(start_decompress));
-    println!("LZ77    Decompression    : {:?}", lz_time_decode.duration_since(huffman_time_decode));
+    // Throughput in MB of uncompressed data of the stage per second.
+    let throughput = |bytes: usize, time: std::time::Duration| bytes as f64 / 2f64.powi(20) / time.as_secs_f64();
+    let timings = [
+        ("LZ77    Compression      ", lz_time.duration_since(start), serialized.len()),
+        ("Huffman Compression      ", lz_huffman_time.duration_since(lz_time), lz_encoded.len()),
+        ("Huffman Decompression    ", huffman_time_decode.duration_since(start_decompress), lz_encoded.len()),
+        ("Huffman bitwise decoding ", huffman_bitwise_time_decode.duration_since(lz_time_decode), lz_encoded.len()),
+        ("FSE     Compression      ", lz_fse_time.duration_since(lz_huffman_time), lz_encoded.len()),
+        ("FSE     Decompression    ", fse_time_decode.duration_since(huffman_bitwise_time_decode), lz_encoded.len()),
+        ("LZ77    Decompression    ", lz_time_decode.duration_since(huffman_time_decode), serialized.len()),
+    ];
+    for (name, time, bytes) in timings {
+        println!("{}: {:?} ({:.1} MB/s)", name, time, throughput(bytes, time));
+    }
     println!("Compression Ratio : {:.2}%", 100.0 * (compressed.len() as f32 / serialized.len() as f32));
-}
\ No newline at end of file
+    println!("FSE Ratio         : {:.2}%", 100.0 * (fse.len().min(lz_encoded.len()) as f32 / serialized.len() as f32));
+    Ok(())
+}
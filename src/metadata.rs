@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde::{Serialize, Deserialize};
+
+/// Seconds and nanoseconds relative to the unix epoch.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Timestamp {
+    pub secs: i64,
+    pub nanos: u32,
+}
+
+impl Timestamp {
+    #[cfg(not(unix))]
+    pub fn from_system_time(time: SystemTime) -> Self {
+        match time.duration_since(UNIX_EPOCH) {
+            Ok(d) => Self { secs: d.as_secs() as i64, nanos: d.subsec_nanos() },
+            Err(e) => {
+                let d = e.duration();
+                match d.subsec_nanos() {
+                    0 => Self { secs: -(d.as_secs() as i64), nanos: 0 },
+                    n => Self { secs: -(d.as_secs() as i64) - 1, nanos: 1_000_000_000 - n },
+                }
+            }
+        }
+    }
+
+    pub fn to_system_time(self) -> SystemTime {
+        if self.secs >= 0 {
+            UNIX_EPOCH + Duration::new(self.secs as u64, self.nanos)
+        } else {
+            UNIX_EPOCH - Duration::from_secs(self.secs.unsigned_abs()) + Duration::from_nanos(self.nanos as u64)
+        }
+    }
+}
+
+/// Permissions, ownership and timestamps of an archive entry.
+/// `ctime` is recorded for information only, it cannot be restored.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Default)]
+pub struct Metadata {
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub atime: Timestamp,
+    pub mtime: Timestamp,
+    pub ctime: Timestamp,
+}
+
+impl Metadata {
+    #[cfg(unix)]
+    pub fn from_fs(metadata: &fs::Metadata) -> Self {
+        use std::os::unix::fs::MetadataExt;
+        Self {
+            mode: metadata.mode(),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            user: user_names().get(&metadata.uid()).cloned(),
+            group: group_names().get(&metadata.gid()).cloned(),
+            atime: Timestamp { secs: metadata.atime(), nanos: metadata.atime_nsec() as u32 },
+            mtime: Timestamp { secs: metadata.mtime(), nanos: metadata.mtime_nsec() as u32 },
+            ctime: Timestamp { secs: metadata.ctime(), nanos: metadata.ctime_nsec() as u32 },
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn from_fs(metadata: &fs::Metadata) -> Self {
+        let time = |t: std::io::Result<SystemTime>| t.map(Timestamp::from_system_time).unwrap_or_default();
+        Self {
+            mode: if metadata.permissions().readonly() { 0o444 } else { 0o644 },
+            atime: time(metadata.accessed()),
+            mtime: time(metadata.modified()),
+            ctime: time(metadata.created()),
+            ..Default::default()
+        }
+    }
+
+    /// Restores ownership, timestamps and permissions on an already written entry.
+    /// Ownership is only changed where the process is allowed to, names are preferred over raw ids.
+    /// Permissions come last so that read-only entries can still be updated before.
+    pub fn apply(&self, path: &Path) {
+        self.apply_ownership(path);
+        let times = fs::FileTimes::new()
+            .set_accessed(self.atime.to_system_time())
+            .set_modified(self.mtime.to_system_time());
+        if let Ok(file) = fs::File::open(path) {
+            file.set_times(times).unwrap_or(());
+        }
+        self.apply_permissions(path);
+    }
+
+    /// Restores the ownership of a symbolic link itself. Everything else would be applied to the link target.
+    pub fn apply_to_link(&self, path: &Path) {
+        #[cfg(unix)]
+        {
+            let (uid, gid) = self.owner_ids();
+            std::os::unix::fs::lchown(path, Some(uid), Some(gid)).unwrap_or(());
+        }
+        #[cfg(not(unix))]
+        let _ = path;
+    }
+
+    #[cfg(unix)]
+    fn owner_ids(&self) -> (u32, u32) {
+        let uid = self.user.as_ref()
+            .and_then(|name| user_names().iter().find(|(_, n)| *n == name).map(|(id, _)| *id))
+            .unwrap_or(self.uid);
+        let gid = self.group.as_ref()
+            .and_then(|name| group_names().iter().find(|(_, n)| *n == name).map(|(id, _)| *id))
+            .unwrap_or(self.gid);
+        (uid, gid)
+    }
+
+    #[cfg(unix)]
+    fn apply_ownership(&self, path: &Path) {
+        let (uid, gid) = self.owner_ids();
+        std::os::unix::fs::chown(path, Some(uid), Some(gid)).unwrap_or(());
+    }
+
+    #[cfg(not(unix))]
+    fn apply_ownership(&self, _path: &Path) {}
+
+    #[cfg(unix)]
+    fn apply_permissions(&self, path: &Path) {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(self.mode & 0o7777)).unwrap_or(());
+    }
+
+    #[cfg(not(unix))]
+    fn apply_permissions(&self, path: &Path) {
+        if let Ok(metadata) = fs::metadata(path) {
+            let mut permissions = metadata.permissions();
+            permissions.set_readonly(self.mode & 0o222 == 0);
+            fs::set_permissions(path, permissions).unwrap_or(());
+        }
+    }
+}
+
+/// Maps ids to names from an `/etc/passwd` style file.
+fn read_id_names(path: &str) -> HashMap<u32, String> {
+    fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(':');
+            let name = fields.next()?;
+            let id = fields.nth(1)?.parse().ok()?;
+            Some((id, name.to_string()))
+        })
+        .collect()
+}
+
+fn user_names() -> &'static HashMap<u32, String> {
+    static NAMES: OnceLock<HashMap<u32, String>> = OnceLock::new();
+    NAMES.get_or_init(|| read_id_names("/etc/passwd"))
+}
+
+fn group_names() -> &'static HashMap<u32, String> {
+    static NAMES: OnceLock<HashMap<u32, String>> = OnceLock::new();
+    NAMES.get_or_init(|| read_id_names("/etc/group"))
+}
@@ -0,0 +1,149 @@
+use std::cell::Cell;
+
+/// A path or glob pattern selecting archive entries.
+///
+/// `*` and `?` match within a single path component, `**` matches across components
+/// and `[...]` matches one character of a set or range, `[!...]` negates the set.
+/// A pattern without any of these characters has to match the path exactly.
+#[derive(Debug)]
+pub struct Pattern {
+    text: String,
+    chars: Vec<char>,
+}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Self {
+        let text = pattern.trim_start_matches("./").trim_end_matches('/').to_string();
+        Pattern {
+            chars: text.chars().collect(),
+            text,
+        }
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        let path = path.chars().collect::<Vec<_>>();
+        let memo = vec![None; (self.chars.len() + 1) * (path.len() + 1)];
+        Matcher { pattern: &self.chars, path: &path, memo }.matches(0, 0)
+    }
+}
+
+/// Matches a pattern against a path from given positions in both. Every pair of positions is
+/// only tried once, several wildcards would otherwise try the same ones over and over again.
+struct Matcher<'a> {
+    pattern: &'a [char],
+    path: &'a [char],
+    /// Results by pattern position times path length plus one, and path position.
+    memo: Vec<Option<bool>>,
+}
+
+impl Matcher<'_> {
+    fn matches(&mut self, p: usize, s: usize) -> bool {
+        let key = p * (self.path.len() + 1) + s;
+        if let Some(matched) = self.memo[key] {
+            return matched;
+        }
+        let matched = self.step(p, s);
+        self.memo[key] = Some(matched);
+        matched
+    }
+
+    fn step(&mut self, p: usize, s: usize) -> bool {
+        let (pattern, path) = (self.pattern, self.path);
+        match pattern.get(p) {
+            None => s == path.len(),
+            Some('*') if pattern.get(p + 1) == Some(&'*') => match pattern.get(p + 2) {
+                // `**/` also matches no directory at all
+                Some('/') => self.matches(p + 3, s)
+                    || (s..path.len()).any(|i| path[i] == '/' && self.matches(p + 3, i + 1)),
+                _ => (s..=path.len()).any(|i| self.matches(p + 2, i)),
+            },
+            Some('*') => (s..=path.len())
+                .take_while(|&i| i == s || path[i - 1] != '/')
+                .any(|i| self.matches(p + 1, i)),
+            Some('?') => path.get(s).is_some_and(|&c| c != '/') && self.matches(p + 1, s + 1),
+            Some('[') => match (class_match(&pattern[p + 1..], path.get(s).copied()), path.get(s)) {
+                (Some((true, len)), Some(_)) => self.matches(p + 1 + len, s + 1),
+                (Some(_), _) => false,
+                // An unclosed bracket is matched literally.
+                (None, Some('[')) => self.matches(p + 1, s + 1),
+                (None, _) => false,
+            },
+            Some(c) => path.get(s) == Some(c) && self.matches(p + 1, s + 1),
+        }
+    }
+}
+
+/// Matches a character class, `pattern` starts after the opening bracket.
+/// Returns whether `c` is in the class and the length of the class including the closing bracket.
+fn class_match(pattern: &[char], c: Option<char>) -> Option<(bool, usize)> {
+    let negated = pattern.first() == Some(&'!');
+    let start = if negated { 1 } else { 0 };
+    // A `]` directly at the start is part of the set.
+    let end = start + 1 + pattern.get(start + 1..)?.iter().position(|&p| p == ']')?;
+    let set = &pattern[start..end];
+    let Some(c) = c.filter(|&c| c != '/') else {
+        return Some((false, end + 1));
+    };
+    let mut found = false;
+    let mut i = 0;
+    while i < set.len() {
+        if i + 2 < set.len() && set[i + 1] == '-' {
+            found |= set[i] <= c && c <= set[i + 2];
+            i += 3;
+        } else {
+            found |= set[i] == c;
+            i += 1;
+        }
+    }
+    Some((found != negated, end + 1))
+}
+
+/// The patterns given on the command line. Without patterns everything is selected.
+///
+/// Patterns are matched against the entry path with and without the archive root name,
+/// so `configs/*.toml` and `bundle/configs/*.toml` both select `bundle/configs/app.toml`.
+/// Selecting a directory selects everything below it.
+#[derive(Debug, Default)]
+pub struct Selection {
+    patterns: Vec<(Pattern, Cell<bool>)>,
+}
+
+impl Selection {
+    pub fn new(patterns: &[String]) -> Self {
+        Selection {
+            patterns: patterns.iter().map(|p| (Pattern::new(p), Cell::new(false))).collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    pub fn selects(&self, path: &str) -> bool {
+        if self.patterns.is_empty() {
+            return true;
+        }
+        let candidates = path.match_indices('/')
+            .map(|(i, _)| &path[..i])
+            .chain(std::iter::once(path))
+            .flat_map(|prefix| [Some(prefix), prefix.split_once('/').map(|(_, rest)| rest)])
+            .flatten()
+            .collect::<Vec<_>>();
+        let mut selected = false;
+        for (pattern, used) in &self.patterns {
+            if candidates.iter().any(|candidate| pattern.matches(candidate)) {
+                used.set(true);
+                selected = true;
+            }
+        }
+        selected
+    }
+
+    /// Patterns that did not select any entry so far.
+    pub fn unused(&self) -> Vec<&str> {
+        self.patterns.iter()
+            .filter(|(_, used)| !used.get())
+            .map(|(pattern, _)| pattern.text.as_str())
+            .collect()
+    }
+}
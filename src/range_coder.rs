@@ -0,0 +1,408 @@
+use rayon::iter::{IndexedParallelIterator, IntoParallelIterator, ParallelIterator};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::lz_77::{Factor, Factors, ParsedChunk, Parser, LZ77};
+use crate::tokens::{bucket, bucket_value, extra_bits, Tokens, BUCKETS};
+
+/// Probabilities are the chance of a 0 bit in units of `2^-PROBABILITY_BITS`.
+const PROBABILITY_BITS: u32 = 11;
+const HALF: u16 = 1 << (PROBABILITY_BITS - 1);
+/// How fast probabilities adapt, they move by `1/2^ADAPT_SHIFT` of the distance to the seen bit.
+const ADAPT_SHIFT: u32 = 5;
+/// The range is renormalized to stay above this.
+const TOP: u32 = 1 << 24;
+
+/// Writes bits with adaptive probabilities, each costing close to its information content, fractions of a bit included.
+pub struct RangeEncoder {
+    low: u64,
+    range: u32,
+    /// The last byte not written yet, it may still change through a carry, followed by `pending - 1` bytes of 0xff.
+    cache: u8,
+    pending: u64,
+    output: Vec<u8>,
+}
+
+impl Default for RangeEncoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RangeEncoder {
+    pub fn new() -> Self {
+        RangeEncoder { low: 0, range: u32::MAX, cache: 0, pending: 1, output: Vec::new() }
+    }
+
+    /// Encodes `bit` and adapts its probability.
+    #[inline]
+    pub fn encode_bit(&mut self, probability: &mut u16, bit: bool) {
+        let bound = (self.range >> PROBABILITY_BITS) * *probability as u32;
+        if bit {
+            self.low += bound as u64;
+            self.range -= bound;
+            *probability -= *probability >> ADAPT_SHIFT;
+        } else {
+            self.range = bound;
+            *probability += ((1 << PROBABILITY_BITS) - *probability) >> ADAPT_SHIFT;
+        }
+        self.normalize();
+    }
+
+    /// Encodes the lowest `bits` bits of `value` with a fixed probability of one half each.
+    pub fn encode_direct(&mut self, value: u32, bits: u8) {
+        for i in (0..bits).rev() {
+            self.range >>= 1;
+            if value >> i & 1 != 0 {
+                self.low += self.range as u64;
+            }
+            self.normalize();
+        }
+    }
+
+    /// Encodes the lowest `bits` bits of `symbol` highest first, every bit with the probability of the
+    /// bits before it. `tree` holds `2^bits` probabilities.
+    pub fn encode_tree(&mut self, tree: &mut [u16], symbol: usize, bits: u8) {
+        let mut node = 1;
+        for i in (0..bits).rev() {
+            let bit = symbol >> i & 1;
+            self.encode_bit(&mut tree[node], bit != 0);
+            node = node << 1 | bit;
+        }
+    }
+
+    #[inline]
+    fn normalize(&mut self) {
+        while self.range < TOP {
+            self.range <<= 8;
+            self.shift_low();
+        }
+    }
+
+    fn shift_low(&mut self) {
+        if (self.low as u32) < 0xff00_0000 || self.low >> 32 != 0 {
+            let carry = (self.low >> 32) as u8;
+            let mut byte = self.cache;
+            while self.pending > 0 {
+                self.output.push(byte.wrapping_add(carry));
+                byte = 0xff;
+                self.pending -= 1;
+            }
+            self.cache = (self.low >> 24) as u8;
+        }
+        self.pending += 1;
+        self.low = (self.low & 0x00ff_ffff) << 8;
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        for _ in 0..5 {
+            self.shift_low();
+        }
+        self.output
+    }
+}
+
+/// Reads the bits of a `RangeEncoder`, with the same probabilities in the same order.
+pub struct RangeDecoder<'a> {
+    input: &'a [u8],
+    position: usize,
+    range: u32,
+    code: u32,
+}
+
+impl<'a> RangeDecoder<'a> {
+    pub fn new(input: &'a [u8]) -> Result<Self> {
+        if input.len() < 5 || input[0] != 0 {
+            return Err(Error::corrupt("invalid range coder stream"));
+        }
+        let code = u32::from_be_bytes(input[1..5].try_into().unwrap());
+        Ok(RangeDecoder { input, position: 5, range: u32::MAX, code })
+    }
+
+    #[inline]
+    pub fn decode_bit(&mut self, probability: &mut u16) -> Result<bool> {
+        let bound = (self.range >> PROBABILITY_BITS) * *probability as u32;
+        let bit = self.code >= bound;
+        if bit {
+            self.code -= bound;
+            self.range -= bound;
+            *probability -= *probability >> ADAPT_SHIFT;
+        } else {
+            self.range = bound;
+            *probability += ((1 << PROBABILITY_BITS) - *probability) >> ADAPT_SHIFT;
+        }
+        self.normalize()?;
+        Ok(bit)
+    }
+
+    pub fn decode_direct(&mut self, bits: u8) -> Result<u32> {
+        let mut value = 0;
+        for _ in 0..bits {
+            self.range >>= 1;
+            let bit = self.code >= self.range;
+            if bit {
+                self.code -= self.range;
+            }
+            value = value << 1 | bit as u32;
+            self.normalize()?;
+        }
+        Ok(value)
+    }
+
+    pub fn decode_tree(&mut self, tree: &mut [u16], bits: u8) -> Result<usize> {
+        let mut node = 1;
+        for _ in 0..bits {
+            node = node << 1 | self.decode_bit(&mut tree[node])? as usize;
+        }
+        Ok(node - (1 << bits))
+    }
+
+    #[inline]
+    fn normalize(&mut self) -> Result<()> {
+        while self.range < TOP {
+            let byte = *self.input.get(self.position).ok_or_else(|| Error::corrupt("unexpected end of range coder stream"))?;
+            self.position += 1;
+            self.range <<= 8;
+            self.code = self.code << 8 | byte as u32;
+        }
+        Ok(())
+    }
+}
+
+/// Bits of the symbols of `tokens::bucket`.
+const BUCKET_BITS: u8 = 7;
+/// Literals are coded in the context of the highest bits of the previous literal.
+const LITERAL_CONTEXT_BITS: u32 = 3;
+/// Distances are coded in the context of their length, up to this many lengths get their own.
+const DISTANCE_CONTEXTS: usize = 4;
+/// The lowest bits of distances are coded with adaptive probabilities, higher extra bits directly.
+const ALIGN_BITS: u8 = 4;
+
+/// Adaptive probabilities for the tokens of a chunk.
+struct Model {
+    /// Whether the next token is a match, in the context of the kinds of the last two tokens.
+    is_match: [u16; 4],
+    /// Whether the last two tokens were matches, one bit each.
+    state: usize,
+    literals: Vec<[u16; 256]>,
+    previous_literal: u8,
+    lengths: [u16; 1 << BUCKET_BITS],
+    distances: [[u16; 1 << BUCKET_BITS]; DISTANCE_CONTEXTS],
+    align: [u16; 1 << ALIGN_BITS],
+}
+
+impl Model {
+    fn new() -> Self {
+        Model {
+            is_match: [HALF; 4],
+            state: 0,
+            literals: vec![[HALF; 256]; 1 << LITERAL_CONTEXT_BITS],
+            previous_literal: 0,
+            lengths: [HALF; 1 << BUCKET_BITS],
+            distances: [[HALF; 1 << BUCKET_BITS]; DISTANCE_CONTEXTS],
+            align: [HALF; 1 << ALIGN_BITS],
+        }
+    }
+
+    fn literals(&mut self) -> &mut [u16; 256] {
+        &mut self.literals[(self.previous_literal >> (8 - LITERAL_CONTEXT_BITS)) as usize]
+    }
+
+    fn distances(&mut self, length: u32) -> &mut [u16; 1 << BUCKET_BITS] {
+        &mut self.distances[(length as usize).saturating_sub(3).min(DISTANCE_CONTEXTS - 1)]
+    }
+
+    fn update(&mut self, is_match: bool) {
+        self.state = (self.state << 1 | is_match as usize) & 3;
+    }
+}
+
+/// LZ77 factors coded like the tokens of `Tokens`, but with an adaptive binary range coder instead
+/// of Huffman codes. Every decision has a probability of its own in a context of what came before it:
+/// flags the kinds of the previous tokens, literals the previous literal, distances their length.
+/// Predictable streams, the flags in particular, take fractions of a bit per symbol.
+///
+/// Every chunk is the token count (32 direct bits) followed by the tokens: the flag, then either the
+/// literal or the length and distance buckets of `tokens::bucket` with their extra bits.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RangeTokens {
+    chunks: Vec<Vec<u8>>,
+    /// CRC32C of every uncompressed chunk.
+    checksums: Vec<u32>,
+}
+
+impl RangeTokens {
+    pub fn serialize(&self) -> Vec<u8> {
+        bincode::serialize(&self).unwrap()
+    }
+
+    pub fn deserialize(input: &[u8]) -> Result<Self> {
+        let tokens: Self = bincode::deserialize(input)?;
+        if tokens.chunks.len() != tokens.checksums.len() {
+            return Err(Error::corrupt("range coder chunk count does not match its checksums"));
+        }
+        Ok(tokens)
+    }
+
+    /// Parses the chunks like `Tokens::encode` and range codes the tokens.
+    pub fn encode(input: &[u8], start: usize, bits: u8, parser: Parser, window: usize) -> RangeTokens {
+        let (chunks, checksums) = LZ77::map_chunks(input, start, bits, window, |chunk, start| {
+            Self::encode_chunk(&Tokens::tokenize(chunk, start, parser))
+        }).into_iter().unzip();
+        RangeTokens { chunks, checksums }
+    }
+
+    fn encode_chunk(tokens: &[(u32, u32)]) -> Vec<u8> {
+        let mut encoder = RangeEncoder::new();
+        let mut model = Model::new();
+        encoder.encode_direct(tokens.len() as u32, 32);
+        for &(value, distance) in tokens {
+            let is_match = distance != 0;
+            encoder.encode_bit(&mut model.is_match[model.state], is_match);
+            model.update(is_match);
+            if !is_match {
+                encoder.encode_tree(model.literals(), value as usize, 8);
+                model.previous_literal = value as u8;
+                continue;
+            }
+            let (symbol, extra, bits) = bucket(value - 1);
+            encoder.encode_tree(&mut model.lengths, symbol, BUCKET_BITS);
+            encoder.encode_direct(bits, extra);
+
+            let (symbol, extra, bits) = bucket(distance - 1);
+            encoder.encode_tree(model.distances(value), symbol, BUCKET_BITS);
+            match extra >= ALIGN_BITS {
+                true => {
+                    encoder.encode_direct(bits >> ALIGN_BITS, extra - ALIGN_BITS);
+                    encoder.encode_tree(&mut model.align, (bits & ((1 << ALIGN_BITS) - 1)) as usize, ALIGN_BITS);
+                },
+                false => encoder.encode_direct(bits, extra),
+            }
+        }
+        encoder.finish()
+    }
+
+    /// Reads the factors of all chunks in parallel, the arguments are those of `LZ77::read`.
+    pub fn read(self, history: usize, window: usize, chunk_size: usize) -> Result<Factors> {
+        let chunks = self.chunks.into_par_iter().zip(self.checksums).enumerate().map(|(i, (chunk, checksum))| {
+            let start = window.min(history + i * chunk_size);
+            let factors = Self::read_chunk(&chunk, start, chunk_size).map_err(|e| e.at(&format!("range coder chunk {}", i)))?;
+            Ok(ParsedChunk { start, factors, checksum })
+        }).collect::<Result<Vec<_>>>()?;
+        Ok(Factors(chunks))
+    }
+
+    /// Reads the factors of a chunk of at most `chunk_size` bytes.
+    fn read_chunk(input: &[u8], start: usize, chunk_size: usize) -> Result<Vec<Factor>> {
+        let mut decoder = RangeDecoder::new(input)?;
+        let mut model = Model::new();
+        let count = decoder.decode_direct(32)? as usize;
+        if count > chunk_size {
+            return Err(Error::corrupt("range coder chunk has too many tokens"));
+        }
+        let mut factors = Vec::with_capacity(count);
+        let mut position = start as u64;
+        for _ in 0..count {
+            let is_match = decoder.decode_bit(&mut model.is_match[model.state])?;
+            model.update(is_match);
+            if !is_match {
+                let literal = decoder.decode_tree(model.literals(), 8)? as u8;
+                model.previous_literal = literal;
+                factors.push((0, 0, literal));
+                position += 1;
+                continue;
+            }
+            let symbol = decoder.decode_tree(&mut model.lengths, BUCKET_BITS)?;
+            if symbol >= BUCKETS {
+                return Err(Error::corrupt("invalid match length"));
+            }
+            let length = bucket_value(symbol, decoder.decode_direct(extra_bits(symbol))?) as u64 + 1;
+
+            let symbol = decoder.decode_tree(model.distances(length.min(u32::MAX as u64) as u32), BUCKET_BITS)?;
+            if symbol >= BUCKETS {
+                return Err(Error::corrupt("invalid match distance"));
+            }
+            let extra = extra_bits(symbol);
+            let bits = match extra >= ALIGN_BITS {
+                true => decoder.decode_direct(extra - ALIGN_BITS)? << ALIGN_BITS | decoder.decode_tree(&mut model.align, ALIGN_BITS)? as u32,
+                false => decoder.decode_direct(extra)?,
+            };
+            let distance = bucket_value(symbol, bits) as u64 + 1;
+            if distance > position || position + length > u32::MAX as u64 {
+                return Err(Error::corrupt("token refers outside of the chunk"));
+            }
+            factors.push(((position - distance) as u32, length as u32, 0));
+            position += length;
+        }
+        Ok(factors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic pseudo random numbers, a linear congruential generator.
+    fn numbers(seed: u64) -> impl Iterator<Item = u32> {
+        std::iter::successors(Some(seed), |x| Some(x.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407))).map(|x| (x >> 33) as u32)
+    }
+
+    #[test]
+    fn bits_round_trip() {
+        // Skewed bits make the probabilities extreme, long runs of them exercise the carry.
+        let values = numbers(7).take(20000).collect::<Vec<_>>();
+        let mut encoder = RangeEncoder::new();
+        let (mut probability, mut tree) = (HALF, vec![HALF; 1 << 6]);
+        for &value in &values {
+            encoder.encode_bit(&mut probability, value % 16 == 0);
+            encoder.encode_direct(value, 13);
+            encoder.encode_tree(&mut tree, (value % 7) as usize, 6);
+        }
+        let output = encoder.finish();
+
+        let mut decoder = RangeDecoder::new(&output).unwrap();
+        let (mut probability, mut tree) = (HALF, vec![HALF; 1 << 6]);
+        for &value in &values {
+            assert_eq!(decoder.decode_bit(&mut probability).unwrap(), value % 16 == 0);
+            assert_eq!(decoder.decode_direct(13).unwrap(), value & ((1 << 13) - 1));
+            assert_eq!(decoder.decode_tree(&mut tree, 6).unwrap(), (value % 7) as usize);
+        }
+    }
+
+    #[test]
+    fn tokens_round_trip() {
+        let text = include_bytes!("lz_77.rs").repeat(3);
+        let random = numbers(3).take(50000).map(|x| x as u8).collect::<Vec<_>>();
+        for input in [&[][..], &[7; 100000], &text, &random] {
+            for parser in [Parser::Fast, Parser::Greedy, Parser::Lazy(2), Parser::Optimal] {
+                for (bits, window) in [(16, 0), (14, 1 << 12)] {
+                    let chunk_size = (1 << bits) - 2;
+                    let encoded = RangeTokens::encode(input, 0, bits, parser, window).serialize();
+                    let decoded = RangeTokens::deserialize(&encoded).unwrap().read(0, window, chunk_size).unwrap().resolve(&[]).unwrap();
+                    assert!(decoded == input, "{:?} with window {}", parser, window);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn history_round_trip() {
+        let text = include_bytes!("range_coder.rs");
+        let start = text.len() / 2;
+        let encoded = RangeTokens::encode(text, start, 16, Parser::Optimal, start).serialize();
+        let factors = RangeTokens::deserialize(&encoded).unwrap().read(start, start, (1 << 16) - 2).unwrap();
+        assert_eq!(factors.resolve(&text[..start]).unwrap(), &text[start..]);
+    }
+
+    #[test]
+    fn corrupt_chunks_fail() {
+        let text = include_bytes!("tokens.rs");
+        let encoded = RangeTokens::encode(text, 0, 16, Parser::Lazy(2), 0).serialize();
+        for (i, position) in numbers(11).take(300).enumerate() {
+            let mut corrupt = encoded.clone();
+            corrupt[40 + position as usize % (encoded.len() - 40)] ^= 1 << (i % 8);
+            let decoded = RangeTokens::deserialize(&corrupt).and_then(|tokens| tokens.read(0, 0, (1 << 16) - 2)).and_then(|factors| factors.resolve(&[]));
+            assert!(decoded.is_err() || decoded.unwrap() == text);
+        }
+    }
+}
@@ -0,0 +1,421 @@
+use std::io::{self, Read, Write};
+
+use rayon::iter::{IndexedParallelIterator, IntoParallelIterator, ParallelIterator};
+
+use crate::crypto::{Cipher, FrameId};
+use crate::error::{Error, Result};
+use crate::header::{Codec, Header, FLAG_INDEX};
+use crate::index::{FrameInfo, Index, IndexEntry, TRAILER_LEN};
+use crate::lz_77::{Factors, Parser, LZ77};
+use crate::range_coder::RangeTokens;
+use crate::tokens::Tokens;
+
+/// Marks the start of a frame, followed by the number of codecs, their ids,
+/// the payload length as little endian `u64` and the payload.
+const FRAME: u8 = 1;
+/// Marks the end of the frame sequence.
+const END: u8 = 0;
+/// With an LZ77 window, every frame whose number is a multiple of this does not refer to earlier
+/// frames, so random access never has to decode more than this many frames before the one it needs.
+pub const WINDOW_RESTART: usize = 16;
+
+/// The data authenticated along with an encrypted frame: its codec ids, whether it is the last frame
+/// and the header. Dropping frames at the end or changing the header makes authentication fail.
+fn associated_data(codecs: &[u8], last: bool, header: &Header) -> Vec<u8> {
+    let mut associated = codecs.to_vec();
+    associated.push(last as u8);
+    associated.extend_from_slice(&header.authenticated());
+    associated
+}
+
+/// Number of frames that are coded at once. Memory use is bounded by this many blocks.
+pub fn batch_size() -> usize {
+    rayon::current_num_threads()
+}
+
+/// Bytes of memory LZ77 and BWT take up per byte of a block while coding it, mostly for the suffix array.
+pub const WORKING_SET_FACTOR: usize = 28;
+/// Memory the blocks a `FrameWriter` codes at once may take up together, 4 GiB.
+pub const MEMORY_BUDGET: usize = 4 << 30;
+
+/// Number of blocks of `block_size` bytes that are coded at once, one per thread as long as
+/// their working set fits `MEMORY_BUDGET`, but at least one.
+pub fn blocks_in_flight(block_size: usize) -> usize {
+    batch_size().min(MEMORY_BUDGET / block_size.saturating_mul(WORKING_SET_FACTOR)).max(1)
+}
+
+/// Number of bytes of the frames before `frame` its LZ77 chunk may refer to.
+/// Every frame except the last holds a full chunk, so this is known without decoding anything.
+pub fn history_len(header: &Header, frame: usize) -> usize {
+    header.window_size().min(frame % WINDOW_RESTART * header.chunk_size())
+}
+
+/// Compresses everything written to it into a sequence of frames, coded independently unless the header has an LZ77 window.
+///
+/// Each frame holds one block of `2^lz_bits - 2` uncompressed bytes, the size of a single LZ77 chunk.
+/// Blocks are buffered until `blocks_in_flight` of them can be coded at once, so at most
+/// `(blocks_in_flight + 1) * block size` bytes of input are held in memory, plus the LZ77 window if
+/// the header has one. Coding takes `WORKING_SET_FACTOR` times the size of the blocks in flight on top.
+/// The last block is only encoded in `finish`, which marks its frame as the last one. There is always at least one frame.
+pub struct FrameWriter<W: Write> {
+    output: W,
+    header: Header,
+    parser: Parser,
+    block_size: usize,
+    /// Number of complete blocks that are coded at once, see `blocks_in_flight`.
+    batch: usize,
+    buffer: Vec<u8>,
+    /// Number of bytes at the start of `buffer` that were already written, kept for the LZ77 window.
+    history: usize,
+    total: u64,
+    /// Number of bytes written to `output`, including the header.
+    position: u64,
+    frames: Vec<FrameInfo>,
+    cipher: Option<Cipher>,
+}
+
+impl<W: Write> FrameWriter<W> {
+    /// Writes the header, which also determines the pipeline and chunk sizes.
+    /// With a cipher every frame is encrypted, the header has to contain the matching `Encryption`.
+    pub fn new(mut output: W, header: Header, parser: Parser, cipher: Option<Cipher>) -> io::Result<Self> {
+        let serialized = header.serialize();
+        output.write_all(&serialized)?;
+        Ok(FrameWriter {
+            output,
+            block_size: header.chunk_size(),
+            batch: blocks_in_flight(header.chunk_size()),
+            header,
+            parser,
+            buffer: Vec::new(),
+            history: 0,
+            total: 0,
+            position: serialized.len() as u64,
+            frames: Vec::new(),
+            cipher,
+        })
+    }
+
+    /// Encodes `block[start..]`, the first stage may refer to the history `block[..start]`.
+    /// Every stage after the first is only kept if it makes the frame smaller.
+    /// Encryption comes last, see `associated_data` for what is authenticated along with the payload.
+    fn encode_frame(block: &[u8], start: usize, header: &Header, parser: Parser, cipher: Option<&Cipher>, id: FrameId, last: bool) -> Vec<u8> {
+        let (&first, rest) = header.pipeline.split_first().expect("the pipeline is never empty");
+        let mut codecs = vec![first as u8];
+        let payload = rest.iter().fold(first.encode(block, start, header, parser), |data, &codec| {
+            let encoded = codec.encode(&data, 0, header, parser);
+            if encoded.len() < data.len() {
+                codecs.push(codec as u8);
+                encoded
+            } else {
+                data
+            }
+        });
+        let payload = match cipher {
+            Some(cipher) => cipher.seal(id, &associated_data(&codecs, last, header), &payload),
+            None => payload,
+        };
+        let mut frame = vec![FRAME, codecs.len() as u8];
+        frame.extend_from_slice(&codecs);
+        frame.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+        frame.extend_from_slice(&payload);
+        frame
+    }
+
+    /// Encodes all complete blocks except the last one, or everything if `all` is set, and writes the frames in order.
+    fn write_frames(&mut self, all: bool) -> io::Result<()> {
+        let pending = self.buffer.len() - self.history;
+        let len = match all {
+            true => pending,
+            false => pending.saturating_sub(1) / self.block_size * self.block_size,
+        };
+        let (header, parser, cipher, first, history) = (&self.header, self.parser, self.cipher.as_ref(), self.frames.len(), self.history);
+        // Empty input still gets a frame, so the last frame of an encrypted archive is never missing.
+        let count = match len {
+            0 if all && first == 0 => 1,
+            _ => len.div_ceil(self.block_size),
+        };
+        let frames = (0..count).into_par_iter()
+            .map(|i| {
+                let (begin, before) = (history + i * self.block_size, history_len(header, first + i));
+                let block_len = self.block_size.min(len - i * self.block_size);
+                let last = all && i + 1 == count;
+                let encoded = Self::encode_frame(&self.buffer[begin - before..begin + block_len], before, header, parser, cipher, FrameId::Data((first + i) as u64), last);
+                (block_len, encoded)
+            })
+            .collect::<Vec<_>>();
+        let mut start = self.total - pending as u64;
+        for (len, frame) in frames {
+            self.frames.push(FrameInfo { offset: self.position, start, len: len as u64 });
+            self.output.write_all(&frame)?;
+            self.position += frame.len() as u64;
+            start += len as u64;
+        }
+        let kept = self.header.window_size().min(history + len);
+        self.buffer.drain(..history + len - kept);
+        self.history = kept;
+        Ok(())
+    }
+
+    /// Writes the remaining data, the end marker and the index built from `entries`.
+    /// Returns the underlying writer and the number of uncompressed bytes.
+    pub fn finish(mut self, entries: Vec<IndexEntry>) -> io::Result<(W, u64)> {
+        self.write_frames(true)?;
+        self.output.write_all(&[END])?;
+        let index = Index { frames: std::mem::take(&mut self.frames), entries }.serialize();
+        self.output.write_all(&Self::encode_frame(&index, 0, &self.header, self.parser, self.cipher.as_ref(), FrameId::Index, true))?;
+        self.output.write_all(&Index::trailer(self.position + 1, crc32c::crc32c(&index)))?;
+        self.output.flush()?;
+        Ok((self.output, self.total))
+    }
+}
+
+impl<W: Write> Write for FrameWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        self.total += buf.len() as u64;
+        if self.buffer.len() - self.history > self.block_size * self.batch {
+            self.write_frames(false)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A frame as read from the archive, not decoded yet.
+pub struct RawFrame {
+    codecs: Vec<Codec>,
+    payload: Vec<u8>,
+}
+
+impl RawFrame {
+    /// Number of bytes the frame takes up in the archive.
+    fn len(&self) -> u64 {
+        (10 + self.codecs.len() + self.payload.len()) as u64
+    }
+}
+
+fn read_exact(input: &mut impl Read, buffer: &mut [u8]) -> Result<()> {
+    input.read_exact(buffer).map_err(|e| Error::from_stream(e, "archive"))
+}
+
+/// Reads the frame at the current position, `None` at the end marker.
+pub fn read_frame(input: &mut impl Read) -> Result<Option<RawFrame>> {
+    let mut byte = [0u8];
+    read_exact(input, &mut byte)?;
+    match byte[0] {
+        END => return Ok(None),
+        FRAME => (),
+        _ => return Err(Error::corrupt(format!("invalid frame marker {}", byte[0]))),
+    }
+    read_exact(input, &mut byte)?;
+    let mut codecs = vec![0; byte[0] as usize];
+    read_exact(input, &mut codecs)?;
+    let codecs = codecs.into_iter().map(Codec::from_id).collect::<Result<Vec<_>>>()?;
+    let mut len = [0u8; 8];
+    read_exact(input, &mut len)?;
+    let len = u64::from_le_bytes(len);
+    let mut payload = Vec::new();
+    input.take(len).read_to_end(&mut payload).map_err(|e| Error::from_stream(e, "archive"))?;
+    if payload.len() as u64 != len {
+        return Err(Error::corrupt("unexpected end of archive"));
+    }
+    Ok(Some(RawFrame { codecs, payload }))
+}
+
+/// A frame decoded except for the references of its LZ77 stage to the frames before it.
+pub enum PendingFrame {
+    Decoded(Vec<u8>),
+    Lz77(Factors, FrameId),
+}
+
+impl PendingFrame {
+    /// Finishes decoding, `history` has to end with the data of the frames before this one.
+    pub fn resolve(self, history: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            PendingFrame::Decoded(data) => Ok(data),
+            PendingFrame::Lz77(factors, id) => factors.resolve(history).map_err(|e| e.at(&id.to_string())),
+        }
+    }
+}
+
+/// Decrypts and decodes a frame as far as possible without the frames before it, which its LZ77
+/// stage may refer to `history` bytes of. `last` tells whether it is the last frame before the end marker,
+/// which encrypted frames authenticate. Errors are prefixed with the frame id.
+pub fn decode_pending(frame: RawFrame, cipher: Option<&Cipher>, id: FrameId, last: bool, header: &Header, history: usize) -> Result<PendingFrame> {
+    let payload = match cipher {
+        Some(cipher) => cipher.open(id, &associated_data(&frame.codecs.iter().map(|&codec| codec as u8).collect::<Vec<_>>(), last, header), &frame.payload)?,
+        None => frame.payload,
+    };
+    let decode = || -> Result<PendingFrame> {
+        let (first, rest) = frame.codecs.split_first().ok_or_else(|| Error::corrupt("frame without codecs"))?;
+        let data = rest.iter().rev().try_fold(payload, |data, codec| codec.decode(&data, header))?;
+        match first {
+            Codec::Lz77 => Ok(PendingFrame::Lz77(LZ77::deserialize(&data)?.read(history, header.window_size(), header.chunk_size())?, id)),
+            Codec::Lz77Huffman => Ok(PendingFrame::Lz77(Tokens::deserialize(&data)?.read(history, header.window_size(), header.chunk_size(), header.code_length_limit())?, id)),
+            Codec::Lz77Range => Ok(PendingFrame::Lz77(RangeTokens::deserialize(&data)?.read(history, header.window_size(), header.chunk_size())?, id)),
+            codec => Ok(PendingFrame::Decoded(codec.decode(&data, header)?)),
+        }
+    };
+    decode().map_err(|e| e.at(&id.to_string()))
+}
+
+/// The end of the decoded data that the LZ77 stage of the next frame may refer to.
+pub struct History {
+    data: Vec<u8>,
+    window: usize,
+}
+
+impl History {
+    pub fn new(header: &Header) -> Self {
+        History { data: Vec::new(), window: header.window_size() }
+    }
+
+    /// Finishes decoding frame `number`, the frames resolved before have to be the ones before it,
+    /// back to the last frame that does not refer to earlier ones.
+    pub fn resolve(&mut self, number: usize, frame: PendingFrame) -> Result<Vec<u8>> {
+        if number.is_multiple_of(WINDOW_RESTART) {
+            self.data.clear();
+        }
+        let data = frame.resolve(&self.data)?;
+        if self.window > 0 {
+            self.data.extend_from_slice(&data);
+            self.data.drain(..self.data.len().saturating_sub(self.window));
+        }
+        Ok(data)
+    }
+}
+
+/// Decrypts and decodes a frame that does not refer to earlier frames and is not followed by others.
+pub fn decode_frame(frame: RawFrame, cipher: Option<&Cipher>, id: FrameId, header: &Header) -> Result<Vec<u8>> {
+    decode_pending(frame, cipher, id, true, header, 0)?.resolve(&[])
+}
+
+/// Decompresses a sequence of frames written by `FrameWriter`, decoding a batch of frames in parallel.
+pub struct FrameReader<R: Read> {
+    input: R,
+    expected_size: u64,
+    decoded: Vec<u8>,
+    pos: usize,
+    frames_read: usize,
+    /// The frame read after the current batch, whether a frame is the last one is only known
+    /// once the next marker is read.
+    next: Option<RawFrame>,
+    /// Position of the next frame to decode in the archive.
+    position: u64,
+    /// The frames decoded so far, to compare them with the index.
+    frames: Vec<FrameInfo>,
+    total: u64,
+    finished: bool,
+    header: Header,
+    history: History,
+    cipher: Option<Cipher>,
+}
+
+impl<R: Read> FrameReader<R> {
+    /// `input` has to be positioned directly after the header.
+    /// Encrypted archives need the cipher derived from the password.
+    pub fn new(input: R, header: &Header, cipher: Option<Cipher>) -> Self {
+        FrameReader {
+            input,
+            expected_size: header.uncompressed_size,
+            decoded: Vec::new(),
+            pos: 0,
+            frames_read: 0,
+            next: None,
+            position: header.serialize().len() as u64,
+            frames: Vec::new(),
+            total: 0,
+            finished: false,
+            header: header.clone(),
+            history: History::new(header),
+            cipher,
+        }
+    }
+
+    /// Reads and decodes the next batch of frames.
+    fn fill(&mut self) -> Result<()> {
+        let mut frames = self.next.take().into_iter().collect::<Vec<_>>();
+        while !self.finished && frames.len() <= batch_size() {
+            match read_frame(&mut self.input)? {
+                Some(frame) => frames.push(frame),
+                None => self.finished = true,
+            }
+        }
+        if !self.finished {
+            self.next = frames.pop();
+        }
+        let (first, cipher, header) = (self.frames_read, self.cipher.as_ref(), &self.header);
+        if cipher.is_some() && first + frames.len() == 0 {
+            return Err(Error::corrupt("the archive has no frames"));
+        }
+        self.frames_read += frames.len();
+        let sizes = frames.iter().map(RawFrame::len).collect::<Vec<_>>();
+        let count = frames.len();
+        let finished = self.finished;
+        let pending = frames.into_par_iter()
+            .enumerate()
+            .map(|(i, frame)| {
+                let last = finished && i + 1 == count;
+                decode_pending(frame, cipher, FrameId::Data((first + i) as u64), last, header, history_len(header, first + i))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.decoded.clear();
+        for (i, frame) in pending.into_iter().enumerate() {
+            let data = self.history.resolve(first + i, frame)?;
+            self.frames.push(FrameInfo { offset: self.position, start: self.total + self.decoded.len() as u64, len: data.len() as u64 });
+            self.position += sizes[i];
+            self.decoded.extend_from_slice(&data);
+        }
+        self.pos = 0;
+        self.total += self.decoded.len() as u64;
+        if self.finished && self.expected_size != 0 && self.total != self.expected_size {
+            return Err(Error::corrupt("decompressed size does not match the header"));
+        }
+        Ok(())
+    }
+
+    /// Number of bytes of the entry stream read so far.
+    pub fn position(&self) -> u64 {
+        self.total - (self.decoded.len() - self.pos) as u64
+    }
+
+    /// Reads the index after the end marker and checks that it describes the frames that were read,
+    /// `None` if the archive has no index. The entry stream has to be read completely before.
+    pub fn read_index(&mut self) -> Result<Option<Index>> {
+        if self.read(&mut [0]).map_err(|e| Error::from_stream(e, "archive"))? != 0 {
+            return Err(Error::corrupt("data after the end of the entry stream"));
+        }
+        if self.header.flags & FLAG_INDEX == 0 {
+            return Ok(None);
+        }
+        let frame = read_frame(&mut self.input)?.ok_or_else(|| Error::corrupt("index is missing"))?;
+        let data = decode_frame(frame, self.cipher.as_ref(), FrameId::Index, &self.header)?;
+        let mut trailer = [0; TRAILER_LEN];
+        read_exact(&mut self.input, &mut trailer)?;
+        let (offset, checksum) = Index::parse_trailer(&trailer)?;
+        // The end marker comes between the last frame and the index.
+        if offset != self.position + 1 {
+            return Err(Error::corrupt("the trailer does not point to the index"));
+        }
+        let index = Index::decode(&data, checksum)?;
+        if index.frames != self.frames {
+            return Err(Error::corrupt("the index does not match the frames of the archive"));
+        }
+        Ok(Some(index))
+    }
+}
+
+impl<R: Read> Read for FrameReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.decoded.len() && !self.finished {
+            self.fill()?;
+        }
+        let len = buf.len().min(self.decoded.len() - self.pos);
+        buf[..len].copy_from_slice(&self.decoded[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
@@ -1,4 +1,4 @@
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 
 /// Folder Archiver and Compression Tool
 #[derive(Parser, Debug)]
@@ -8,19 +8,193 @@ pub struct Args {
     #[arg(short, long)]
     pub encrypt: Option<String>,
 
-    /// Decrypt the file at the given path 
+    /// Path of the archive written by --encrypt, `-` for stdout [default: <folder name>.tmy]
+    #[arg(short, long)]
+    pub output: Option<String>,
+
+    /// Encrypt the archive written by --encrypt with a password that is asked for
+    #[arg(short, long)]
+    pub password: bool,
+
+    /// Use the content of this file as password instead of asking for one.
+    /// Encrypts the archive written by --encrypt and decrypts encrypted archives
+    #[arg(short, long)]
+    pub key_file: Option<String>,
+
+    /// Decrypt the file at the given path, `-` for stdin. Archive paths of the other commands accept `-` as well
     #[arg(short, long)]
     pub decrypt: Option<String>,
 
+    /// Directory --decrypt extracts into, created if missing
+    #[arg(short = 'C', long, default_value = ".")]
+    pub directory: String,
+
+    /// What --decrypt does with entries that already exist, existing directories are always merged
+    #[arg(long, value_enum, default_value = "skip")]
+    pub on_conflict: OnConflict,
+
+    /// Extract absolute paths, `..` components and entries below extracted symbolic links.
+    /// Only use this for archives you trust
+    #[arg(long = "unsafe")]
+    pub allow_unsafe: bool,
+
+    /// Write the content of the files selected by the patterns in the archive at the given path to stdout
+    #[arg(short, long)]
+    pub cat: Option<String>,
+
+    /// List the contents of the archive at the given path
+    #[arg(short = 't', long)]
+    pub list: Option<String>,
+
+    /// Output format of --list
+    #[arg(long, value_enum, default_value = "long")]
+    pub format: ListFormat,
+
+    /// Check the archive at the given path for damage without extracting it
+    #[arg(short, long)]
+    pub verify: Option<String>,
+
+    /// Print the header of the archive at the given path
+    #[arg(short, long)]
+    pub info: Option<String>,
+
     /// Benchmark the folder at the given path
     #[arg(short, long)]
     pub benchmark: Option<String>,
 
-    /// The size of the LZ77 buffer (8-31) 
-    #[arg(short, long, default_value = "28")]
-    pub lz_buffer: u32,
+    /// Follow symbolic links instead of storing them as links
+    #[arg(long)]
+    pub follow_links: bool,
+
+    /// Paths or glob patterns of the entries to extract with --decrypt or --cat, everything if empty
+    pub patterns: Vec<String>,
+
+    /// Compression level of --encrypt, from 0 (store only) to 9 (smallest). Without a level the
+    /// buffer sizes below and the greedy parser are used
+    #[arg(short = 'L', long, value_parser = clap::value_parser!(u8).range(0..=tmy::MAX_LEVEL as i64), conflicts_with_all = ["fast", "best"])]
+    pub level: Option<u8>,
+
+    /// Same as --level 1
+    #[arg(long, conflicts_with = "best")]
+    pub fast: bool,
+
+    /// Same as --level 9
+    #[arg(long)]
+    pub best: bool,
+
+    /// Compress with the Burrows–Wheeler transform instead of LZ77, often smaller on text and logs
+    /// but slower. The LZ77 buffer size sets the block size
+    #[arg(long)]
+    pub bwt: bool,
+
+    /// Code with FSE (tANS) instead of Huffman, decodes faster at about the same size.
+    /// The Huffman buffer size sets the FSE block size. Levels 0, 1 and 9 have no Huffman stage to replace
+    #[arg(long, conflicts_with = "bwt")]
+    pub fse: bool,
+
+    /// The size of the LZ77 buffer (8-31), overrides the level [default: 28].
+    /// Compressing a buffer takes about 28 times its size in memory. Buffers are compressed in
+    /// parallel as long as they fit in 4 GiB, at least one at a time, so the peak is about 7 GiB at 28
+    #[arg(short, long)]
+    pub lz_buffer: Option<u8>,
+
+    /// Let LZ77 refer to this many bytes before every buffer (8-31, as a power of two) to find
+    /// repetitions between buffers, 0 keeps them independent. Overrides the level [default: 0]
+    #[arg(long)]
+    pub lz_window: Option<u8>,
+
+    /// The size of the Huffman buffer (8-31), overrides the level [default: 20]
+    #[arg(long)]
+    pub huffman_buffer: Option<u8>,
+
+    /// The longest Huffman code in bits (9-20), shorter limits speed up decoding [default: 15]
+    #[arg(long)]
+    pub code_length_limit: Option<u8>,
+}
+
+/// What to do when an extracted entry already exists, see `tmy::OnConflict`.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum OnConflict {
+    /// Keep the existing entry
+    Skip,
+    /// Replace the existing entry
+    Overwrite,
+    /// Replace the existing entry if the extracted one was modified later
+    Newer,
+    /// Extract under the first free name with a numeric suffix, `name.1`, `name.2`, ...
+    Rename,
+    /// Stop with an error
+    Abort,
+}
+
+impl From<OnConflict> for tmy::OnConflict {
+    fn from(on_conflict: OnConflict) -> Self {
+        match on_conflict {
+            OnConflict::Skip => tmy::OnConflict::Skip,
+            OnConflict::Overwrite => tmy::OnConflict::Overwrite,
+            OnConflict::Newer => tmy::OnConflict::Newer,
+            OnConflict::Rename => tmy::OnConflict::Rename,
+            OnConflict::Abort => tmy::OnConflict::Abort,
+        }
+    }
+}
+
+/// Output format of --list, see `tmy::listing::ListFormat`.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum ListFormat {
+    /// Permissions, owner, size, modification time and path
+    Long,
+    /// One path per line
+    Plain,
+    /// A JSON array with one object per entry
+    Json,
+}
+
+impl From<ListFormat> for tmy::listing::ListFormat {
+    fn from(format: ListFormat) -> Self {
+        match format {
+            ListFormat::Long => tmy::listing::ListFormat::Long,
+            ListFormat::Plain => tmy::listing::ListFormat::Plain,
+            ListFormat::Json => tmy::listing::ListFormat::Json,
+        }
+    }
+}
+
+impl Args {
+    /// The level given by --level, --fast or --best.
+    pub fn level(&self) -> Option<u8> {
+        match (self.level, self.fast, self.best) {
+            (Some(level), _, _) => Some(level),
+            (None, true, _) => Some(1),
+            (None, _, true) => Some(tmy::MAX_LEVEL),
+            _ => None,
+        }
+    }
 
-    /// The size of the Huffman buffer (8-31)
-    #[arg(long, default_value = "20")]
-    pub huffman_buffer: u32,
-}
\ No newline at end of file
+    /// Compression options from the level and the buffer sizes.
+    pub fn options(&self) -> tmy::Options {
+        let mut options = tmy::Options::new().follow_links(self.follow_links);
+        if let Some(level) = self.level() {
+            options = options.level(level);
+        }
+        if self.bwt {
+            options = options.pipeline(vec![tmy::Codec::Bwt]);
+        }
+        if self.fse {
+            options = options.fse();
+        }
+        if let Some(bits) = self.lz_buffer {
+            options = options.lz_bits(bits);
+        }
+        if let Some(bits) = self.lz_window {
+            options = options.lz_window(bits);
+        }
+        if let Some(bits) = self.huffman_buffer {
+            options = options.huffman_bits(bits);
+        }
+        if let Some(bits) = self.code_length_limit {
+            options = options.code_length_limit(bits);
+        }
+        options
+    }
+}
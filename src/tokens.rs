@@ -0,0 +1,263 @@
+use rayon::iter::{IndexedParallelIterator, IntoParallelIterator, ParallelIterator};
+use serde::{Deserialize, Serialize};
+
+use crate::bitbuffer::BitBuffer;
+use crate::error::{Error, Result};
+use crate::huffman::CanonicalCode;
+use crate::lz_77::{Factor, Factors, ParsedChunk, Parser, LZ77};
+
+/// Number of tokens that share one pair of Huffman codes.
+const BLOCK_TOKENS: usize = 1 << 16;
+/// Values below this have a symbol of their own, see `bucket`.
+const DIRECT_VALUES: u32 = 8;
+/// Symbols needed for any `u32` value.
+pub(crate) const BUCKETS: usize = DIRECT_VALUES as usize + 2 * (32 - 3);
+/// Literal bytes followed by the match length buckets.
+const LITERAL_LENGTH_SYMBOLS: usize = 256 + BUCKETS;
+/// Fraction bits of the costs of the optimal parse.
+const COST_SHIFT: u32 = 4;
+
+/// Symbol and extra bits of a value. Values below `DIRECT_VALUES` have their own symbol, larger
+/// ones share it with the values of the same bit length and second highest bit.
+pub(crate) fn bucket(value: u32) -> (usize, u8, u32) {
+    if value < DIRECT_VALUES {
+        return (value as usize, 0, 0);
+    }
+    let high = 31 - value.leading_zeros();
+    let extra = high - 1;
+    let symbol = DIRECT_VALUES as usize + 2 * (high - 3) as usize + (value >> extra & 1) as usize;
+    (symbol, extra as u8, value & ((1 << extra) - 1))
+}
+
+/// Number of extra bits after a `bucket` symbol, which has to be below `BUCKETS`.
+pub(crate) fn extra_bits(symbol: usize) -> u8 {
+    match symbol < DIRECT_VALUES as usize {
+        true => 0,
+        false => ((symbol - DIRECT_VALUES as usize) / 2 + 2) as u8,
+    }
+}
+
+/// The value of a `bucket` symbol and its extra bits.
+pub(crate) fn bucket_value(symbol: usize, extra: u32) -> u32 {
+    if symbol < DIRECT_VALUES as usize {
+        return symbol as u32;
+    }
+    let high = ((symbol - DIRECT_VALUES as usize) / 2 + 3) as u32;
+    let second = ((symbol - DIRECT_VALUES as usize) % 2) as u32;
+    1 << high | second << (high - 1) | extra
+}
+
+/// Reads the value of a `bucket` symbol.
+fn unbucket(symbol: usize, input: &mut BitBuffer) -> Result<u32> {
+    Ok(bucket_value(symbol, input.read_bits(extra_bits(symbol))?))
+}
+
+/// Bits of the tokens for the optimal parse, in fixed point with `COST_SHIFT` fraction bits. The
+/// parse cannot price a match by its length, so every match costs the average length of the tokens
+/// the costs come from.
+struct Costs {
+    literals: [u64; 256],
+    length: u64,
+    distances: [u64; BUCKETS],
+}
+
+impl Costs {
+    /// Estimates before any parse: 8 bits for a literal, 12 for a match and the extra bits of its distance.
+    fn initial() -> Costs {
+        Costs { literals: [8 << COST_SHIFT; 256], length: 12 << COST_SHIFT, distances: [0; BUCKETS] }
+    }
+
+    /// The information content of the symbols of `tokens`, as both `Tokens` and `RangeTokens` code
+    /// them: literals and length buckets share one alphabet, distance buckets have another. Every
+    /// symbol is counted once more, so that those the tokens lack are not free.
+    fn from_tokens(tokens: &[(u32, u32)]) -> Costs {
+        let mut literal_counts = [1u64; LITERAL_LENGTH_SYMBOLS];
+        let mut distance_counts = [1u64; BUCKETS];
+        let mut length_extra = 0;
+        for &(value, distance) in tokens {
+            match distance {
+                0 => literal_counts[value as usize] += 1,
+                _ => {
+                    let (symbol, extra, _) = bucket(value - 1);
+                    literal_counts[256 + symbol] += 1;
+                    length_extra += extra as u64;
+                    distance_counts[bucket(distance - 1).0] += 1;
+                },
+            }
+        }
+        let bits = |counts: &[u64]| {
+            let total = counts.iter().sum::<u64>() as f64;
+            counts.iter().map(|&count| ((total / count as f64).log2() * (1 << COST_SHIFT) as f64) as u64).collect::<Vec<_>>()
+        };
+        let (literal_bits, distance_bits) = (bits(&literal_counts), bits(&distance_counts));
+        let lengths = &literal_counts[256..];
+        let matches = lengths.iter().map(|count| count - 1).sum::<u64>();
+        let length_bits = lengths.iter().zip(&literal_bits[256..]).map(|(&count, &bits)| (count - 1) * bits).sum::<u64>();
+        Costs {
+            literals: literal_bits[..256].try_into().unwrap(),
+            length: match matches {
+                0 => Self::initial().length,
+                _ => (length_bits + (length_extra << COST_SHIFT)) / matches,
+            },
+            distances: distance_bits.try_into().unwrap(),
+        }
+    }
+
+    /// Bits of a match at `distance`.
+    fn matched(&self, distance: u32) -> u64 {
+        let (symbol, extra, _) = bucket(distance - 1);
+        self.length + self.distances[symbol] + ((extra as u64) << COST_SHIFT)
+    }
+}
+
+/// LZ77 factors coded like DEFLATE: literals and match lengths share one Huffman code, match
+/// distances have another. Both are built anew for every block of `BLOCK_TOKENS` tokens, so the
+/// entropy coder sees whole symbols instead of the bit packed stream of `LZ77`.
+///
+/// Every chunk is a sequence of blocks: token count (32 bits), the code lengths of the literal and
+/// length code and of the distance code as written by `CanonicalCode::write_lengths`, then the tokens. A match is its length
+/// bucket in the literal code with extra bits, followed by the distance bucket with extra bits.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Tokens {
+    chunks: Vec<BitBuffer>,
+    /// CRC32C of every uncompressed chunk.
+    checksums: Vec<u32>,
+}
+
+impl Tokens {
+    pub fn serialize(&self) -> Vec<u8> {
+        bincode::serialize(&self).unwrap()
+    }
+
+    pub fn deserialize(input: &[u8]) -> Result<Self> {
+        let tokens: Self = bincode::deserialize(input)?;
+        if tokens.chunks.len() != tokens.checksums.len() {
+            return Err(Error::corrupt("token chunk count does not match its checksums"));
+        }
+        tokens.chunks.iter().enumerate().try_for_each(|(i, chunk)| chunk.validate().map_err(|e| e.at(&format!("token chunk {}", i))))?;
+        Ok(tokens)
+    }
+
+    /// Parses the chunks like `LZ77::encode_with_history` and codes the factors as tokens, with Huffman
+    /// codes of at most `max_length` bits.
+    pub fn encode(input: &[u8], start: usize, bits: u8, parser: Parser, window: usize, max_length: u8) -> Tokens {
+        let (chunks, checksums) = LZ77::map_chunks(input, start, bits, window, |chunk, start| {
+            Self::encode_chunk(&Self::tokenize(chunk, start, parser), max_length)
+        }).into_iter().unzip();
+        Tokens { chunks, checksums }
+    }
+
+    /// Parses `input[start..]` into literals `(byte, 0)` and matches `(length, distance)`.
+    pub(crate) fn tokenize(input: &[u8], start: usize, parser: Parser) -> Vec<(u32, u32)> {
+        match parser {
+            Parser::Optimal => Self::optimal_tokens(input, start),
+            parser => Self::from_factors(input, start, LZ77::parse(input, start, parser).0),
+        }
+    }
+
+    /// The tokens of the factors of `input[start..]`. Short matches far away cost more than their
+    /// literals and are split into them.
+    fn from_factors(input: &[u8], start: usize, factors: Vec<Factor>) -> Vec<(u32, u32)> {
+        let mut position = start;
+        factors.into_iter().flat_map(|(p, l, c)| {
+            let here = position;
+            position += l.max(1) as usize;
+            if l == 0 {
+                // Literals may carry a leftover candidate, even one past the chunk.
+                return vec![(c as u32, 0)];
+            }
+            debug_assert!((p as usize) < here);
+            match (l, here as u32 - p) {
+                (1..=2, _) | (3, 4097..) => input[p as usize..][..l as usize].iter().map(|&c| (c as u32, 0)).collect(),
+                (l, distance) => vec![(l, distance)],
+            }
+        }).collect()
+    }
+
+    /// The optimal parse of `LZ77` from both candidate matches, which are as long as the input allows.
+    /// The codes are only known once the tokens are, so this parses twice: with the `Costs::initial`
+    /// estimates, then with the costs of the symbols of that parse.
+    fn optimal_tokens(input: &[u8], start: usize) -> Vec<(u32, u32)> {
+        let matches = LZ77::optimal_matches(input, start, |_| usize::MAX);
+        let parse = |costs: &Costs| {
+            let factors = LZ77::shortest_parse(input, start, &matches, 2, |i| costs.literals[input[i] as usize], |i, p| costs.matched(i as u32 - p)).1;
+            Self::from_factors(input, start, factors)
+        };
+        parse(&Costs::from_tokens(&parse(&Costs::initial())))
+    }
+
+    fn encode_chunk(tokens: &[(u32, u32)], max_length: u8) -> BitBuffer {
+        let mut output = BitBuffer::new();
+        let blocks = tokens.chunks(BLOCK_TOKENS).collect::<Vec<_>>();
+        output.write_bits(blocks.len() as u32, 32);
+        for block in blocks {
+            let mut literal_counts = vec![0u64; LITERAL_LENGTH_SYMBOLS];
+            let mut distance_counts = vec![0u64; BUCKETS];
+            for &(value, distance) in block {
+                match distance {
+                    0 => literal_counts[value as usize] += 1,
+                    _ => {
+                        literal_counts[256 + bucket(value - 1).0] += 1;
+                        distance_counts[bucket(distance - 1).0] += 1;
+                    },
+                }
+            }
+            let (literal_code, distance_code) = (CanonicalCode::from_counts(&literal_counts, max_length), CanonicalCode::from_counts(&distance_counts, max_length));
+            output.write_bits(block.len() as u32, 32);
+            literal_code.write_lengths(&mut output);
+            distance_code.write_lengths(&mut output);
+            for &(value, distance) in block {
+                if distance == 0 {
+                    literal_code.write(value as usize, &mut output);
+                    continue;
+                }
+                for (code, offset, value) in [(&literal_code, 256, value - 1), (&distance_code, 0, distance - 1)] {
+                    let (symbol, extra, bits) = bucket(value);
+                    code.write(offset + symbol, &mut output);
+                    output.write_bits(bits, extra);
+                }
+            }
+        }
+        output
+    }
+
+    /// Reads the factors of all chunks in parallel, the arguments are those of `LZ77::read` and the
+    /// length limit of the Huffman codes.
+    pub fn read(self, history: usize, window: usize, chunk_size: usize, max_length: u8) -> Result<Factors> {
+        let chunks = self.chunks.into_par_iter().zip(self.checksums).enumerate().map(|(i, (chunk, checksum))| {
+            let start = window.min(history + i * chunk_size);
+            let factors = Self::read_chunk(chunk, start, max_length).map_err(|e| e.at(&format!("token chunk {}", i)))?;
+            Ok(ParsedChunk { start, factors, checksum })
+        }).collect::<Result<Vec<_>>>()?;
+        Ok(Factors(chunks))
+    }
+
+    fn read_chunk(mut input: BitBuffer, start: usize, max_length: u8) -> Result<Vec<Factor>> {
+        let mut factors = Vec::new();
+        let mut position = start as u64;
+        for _ in 0..input.read_bits(32)? {
+            let count = input.read_bits(32)?;
+            if count as usize > BLOCK_TOKENS {
+                return Err(Error::corrupt("token block too long"));
+            }
+            let literal_code = CanonicalCode::read_lengths(&mut input, LITERAL_LENGTH_SYMBOLS, max_length)?.decoder();
+            let distance_code = CanonicalCode::read_lengths(&mut input, BUCKETS, max_length)?.decoder();
+            for _ in 0..count {
+                let symbol = literal_code.read(&mut input)?;
+                if symbol < 256 {
+                    factors.push((0, 0, symbol as u8));
+                    position += 1;
+                    continue;
+                }
+                let length = unbucket(symbol - 256, &mut input)? as u64 + 1;
+                let distance = unbucket(distance_code.read(&mut input)?, &mut input)? as u64 + 1;
+                if distance > position || position + length > u32::MAX as u64 {
+                    return Err(Error::corrupt("token refers outside of the chunk"));
+                }
+                factors.push(((position - distance) as u32, length as u32, 0));
+                position += length;
+            }
+        }
+        Ok(factors)
+    }
+}
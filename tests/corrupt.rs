@@ -0,0 +1,150 @@
+use std::io::Cursor;
+
+use tmy::archive::Entry;
+use tmy::index::{Index, IndexEntry};
+use tmy::{Codec, Header, IndexedArchive, Options};
+
+const PASSWORD: &[u8] = b"secret";
+
+/// An encrypted container with several small frames.
+fn encrypted() -> Vec<u8> {
+    let data = (0..4000u32).flat_map(|i| (i % 251).to_le_bytes()).collect::<Vec<_>>();
+    Options::new().pipeline(vec![Codec::Store]).lz_bits(10).password(PASSWORD).encode(&data).unwrap()
+}
+
+/// The offsets at which the frames after the header end, see `stream::read_frame`.
+fn frame_ends(data: &[u8]) -> Vec<usize> {
+    let (_, mut pos) = Header::deserialize(data).unwrap();
+    let mut ends = Vec::new();
+    while data[pos] == 1 {
+        let codecs = data[pos + 1] as usize;
+        let len = u64::from_le_bytes(data[pos + 2 + codecs..pos + 10 + codecs].try_into().unwrap()) as usize;
+        pos += 10 + codecs + len;
+        ends.push(pos);
+    }
+    ends
+}
+
+/// Rewrites the index of an archive with the `Store` pipeline, whose index frame is the serialized index.
+fn change_index(archive: &[u8], change: impl FnOnce(&mut Index)) -> Vec<u8> {
+    let (header, _) = Header::deserialize(archive).unwrap();
+    let mut index = Index::read(&mut Cursor::new(archive), &header, None).unwrap();
+    change(&mut index);
+    let offset = u64::from_le_bytes(archive[archive.len() - 16..archive.len() - 8].try_into().unwrap());
+    let data = index.serialize();
+    let frame = [&[1, 1, Codec::Store as u8][..], &(data.len() as u64).to_le_bytes(), &data].concat();
+    [&archive[..offset as usize], &frame, &Index::trailer(offset, crc32c::crc32c(&data))].concat()
+}
+
+#[test]
+fn header_chunk_bits_are_checked() {
+    for (lz_bits, huffman_bits) in [(7, 14), (32, 14), (16, 0), (16, 255)] {
+        let bytes = Header::new(vec![Codec::Lz77Huffman], lz_bits, huffman_bits, 0).serialize();
+        assert!(Header::deserialize(&bytes).is_err(), "lz_bits {} and huffman_bits {} accepted", lz_bits, huffman_bits);
+    }
+    let bytes = Header::new(vec![Codec::Lz77Huffman], 16, 14, 0).serialize();
+    assert!(Header::deserialize(&bytes).is_ok());
+}
+
+#[test]
+fn encrypted_archives_are_authenticated() {
+    let archive = encrypted();
+    assert!(tmy::decode(&archive, Some(PASSWORD)).is_ok());
+
+    // Frames dropped at the end, followed by a new end marker.
+    let ends = frame_ends(&archive);
+    assert!(ends.len() > 2);
+    for end in [ends[0], ends[ends.len() - 2]] {
+        let truncated = [&archive[..end], &[0]].concat();
+        assert!(tmy::decode(&truncated, Some(PASSWORD)).is_err(), "truncated at {} accepted", end);
+    }
+    let (header, len) = Header::deserialize(&archive).unwrap();
+    let empty = [&archive[..len], &[0]].concat();
+    assert!(tmy::decode(&empty, Some(PASSWORD)).is_err());
+
+    // A changed header with a valid checksum.
+    let changed = Header { huffman_bits: header.huffman_bits + 1, ..header.clone() }.serialize();
+    let tampered = [&changed, &archive[len..]].concat();
+    assert!(tmy::decode(&tampered, Some(PASSWORD)).is_err());
+
+    // Key derivation parameters beyond the limits are refused before deriving anything.
+    for (memory, iterations) in [(u32::MAX, 2), (19 * 1024, u32::MAX)] {
+        let mut header = header.clone();
+        let encryption = header.encryption.as_mut().unwrap();
+        (encryption.memory, encryption.iterations) = (memory, iterations);
+        assert!(Header::deserialize(&header.serialize()).is_err());
+    }
+}
+
+#[test]
+fn header_code_length_limit_is_checked() {
+    for (limit, valid) in [(8, false), (9, true), (20, true), (21, false), (30, false)] {
+        let mut header = Header::new(vec![Codec::Lz77Huffman], 16, 14, 0);
+        header.set_code_length_limit(limit);
+        assert_eq!(Header::deserialize(&header.serialize()).is_ok(), valid, "code length limit {}", limit);
+    }
+}
+
+#[test]
+fn code_lengths_beyond_the_default_limit_need_the_flag() {
+    // Fibonacci counts give the longest possible codes, here up to the limit of 20 bits.
+    let (mut a, mut b) = (1, 1);
+    let mut data = Vec::new();
+    for symbol in 0..26u8 {
+        data.extend(std::iter::repeat_n(symbol, a));
+        (a, b) = (b, a + b);
+    }
+    let archive = Options::new().pipeline(vec![Codec::Huffman]).code_length_limit(20).encode(&data).unwrap();
+    assert_eq!(tmy::decode(&archive, None).unwrap(), data);
+
+    // Without the flag the default limit applies and the longer codes are refused.
+    let (header, len) = Header::deserialize(&archive).unwrap();
+    let cleared = Header { flags: header.flags & !tmy::header::FLAG_CODE_LENGTH_LIMIT, ..header }.serialize();
+    let tampered = [&cleared, &archive[len..]].concat();
+    assert!(matches!(tmy::decode(&tampered, None), Err(tmy::Error::Corrupt(_))));
+}
+
+#[test]
+fn index_positions_are_checked() {
+    let data = (0..4000u32).flat_map(|i| i.to_le_bytes()).collect::<Vec<_>>();
+    let archive = Options::new().pipeline(vec![Codec::Store]).lz_bits(10).encode(&data).unwrap();
+    let (header, _) = Header::deserialize(&archive).unwrap();
+    let open = |archive: Vec<u8>| IndexedArchive::open(Cursor::new(archive), &header, None).map(|_| ());
+    assert!(open(change_index(&archive, |_| ())).is_ok());
+
+    fn file(content_offset: u64, size: u64) -> IndexEntry {
+        IndexEntry { entry: Entry::File { path: "f".to_string(), metadata: Default::default(), size }, content_offset }
+    }
+    let changes: [fn(&mut Index); 4] = [
+        |index| index.frames[1].start += 1,
+        |index| index.frames.last_mut().unwrap().len = u64::MAX,
+        |index| index.entries.push(file(u64::MAX - 2, 0)),
+        |index| index.entries.push(file(10, u64::MAX)),
+    ];
+    for change in changes {
+        assert!(matches!(open(change_index(&archive, change)), Err(tmy::Error::Corrupt(_))));
+    }
+}
+
+#[test]
+fn verify_compares_the_index() {
+    let source = std::env::temp_dir().join(format!("tmy-test-{}-verify", std::process::id()));
+    let _ = std::fs::remove_dir_all(&source);
+    std::fs::create_dir_all(source.join("sub")).unwrap();
+    std::fs::write(source.join("a.txt"), b"first").unwrap();
+    std::fs::write(source.join("sub/b.txt"), b"second").unwrap();
+    let (archive, _) = Options::new().pipeline(vec![Codec::Store]).lz_bits(8).write_archive(source.to_str().unwrap(), Vec::new()).unwrap();
+    std::fs::remove_dir_all(&source).unwrap();
+    let verify = |archive: &[u8]| tmy::ArchiveReader::new(archive)?.verify();
+    assert_eq!(verify(&change_index(&archive, |_| ())).unwrap(), Vec::<String>::new());
+
+    // Each index is consistent in itself, but does not describe the archive.
+    let changes: [fn(&mut Index); 3] = [
+        |index| index.entries[1].content_offset += 1,
+        |index| { index.entries.pop(); },
+        |index| index.frames[0].offset += 1,
+    ];
+    for change in changes {
+        assert!(matches!(verify(&change_index(&archive, change)), Err(tmy::Error::Corrupt(_))));
+    }
+}
@@ -0,0 +1,201 @@
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+use tmy::archive::EntryWriter;
+use tmy::metadata::Metadata;
+use tmy::{Archive, ArchiveReader, Entry, Error, ExtractEvent, ExtractOptions, IndexedArchive, ExtractSummary, OnConflict, Options, Selection};
+
+/// An empty directory of its own for every test.
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("tmy-test-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Archives the directory `source` into memory.
+fn archive(source: &Path) -> Vec<u8> {
+    Options::new().write_archive(source.to_str().unwrap(), Vec::new()).unwrap().0
+}
+
+fn extract(archive: &[u8], directory: &Path, patterns: &[&str], on_conflict: OnConflict) -> tmy::Result<ExtractSummary> {
+    let patterns = patterns.iter().map(|pattern| pattern.to_string()).collect::<Vec<_>>();
+    let options = ExtractOptions { on_conflict, allow_unsafe: false };
+    ArchiveReader::new(archive)?.extract(directory.to_str().unwrap(), &Selection::new(&patterns), options)
+}
+
+#[test]
+fn skipped_directory_skips_its_children() {
+    let dir = scratch("skipped-directory");
+    let source = dir.join("d");
+    fs::create_dir_all(source.join("sub/deeper")).unwrap();
+    fs::write(source.join("sub/file.txt"), b"inside").unwrap();
+    fs::write(source.join("sub/deeper/more.txt"), b"deeper").unwrap();
+    fs::write(source.join("top.txt"), b"top").unwrap();
+    let archive = archive(&source);
+
+    // A file stands where the archive has a directory.
+    let output = dir.join("out");
+    fs::create_dir_all(output.join("d")).unwrap();
+    fs::write(output.join("d/sub"), b"in the way").unwrap();
+    let summary = extract(&archive, &output, &[], OnConflict::Skip).unwrap();
+
+    assert_eq!(fs::read(output.join("d/sub")).unwrap(), b"in the way");
+    assert_eq!(fs::read(output.join("d/top.txt")).unwrap(), b"top");
+    // sub, sub/deeper and the two files below them.
+    assert_eq!(summary.skipped, 4);
+    assert_eq!(summary.created, 1);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+/// An indexed archive of `source` whose selected entries are streamed through the index.
+fn indexed(source: &Path) -> IndexedArchive<Cursor<Vec<u8>>> {
+    let (archive, _) = Options::new().write_archive_seekable(source.to_str().unwrap(), Cursor::new(Vec::new())).unwrap();
+    let archive = Cursor::new(archive.into_inner());
+    ArchiveReader::new(archive).unwrap().indexed().unwrap().unwrap()
+}
+
+#[test]
+fn selected_hard_link_gets_its_content() {
+    let dir = scratch("hard-link");
+    let source = dir.join("d");
+    fs::create_dir_all(&source).unwrap();
+    fs::write(source.join("a.txt"), b"linked").unwrap();
+    fs::hard_link(source.join("a.txt"), source.join("b.txt")).unwrap();
+    let selection = Selection::new(&["d/b.txt".to_string()]);
+
+    let output = dir.join("out");
+    fs::create_dir_all(&output).unwrap();
+    let options = ExtractOptions { on_conflict: OnConflict::Abort, allow_unsafe: false };
+    let summary = Archive::stream_to_disk(indexed(&source).select(&selection), output.to_str().unwrap(), &selection, options).unwrap();
+    assert_eq!(fs::read(output.join("d/b.txt")).unwrap(), b"linked");
+    assert!(!output.join("d/a.txt").exists());
+    assert_eq!(summary.created, 2);
+
+    let mut content = Vec::new();
+    Archive::cat_stream(indexed(&source).select_contents(&selection), &selection, &mut content).unwrap();
+    assert_eq!(content, b"linked");
+
+    // Front to back the link is skipped, its target is not there.
+    let output = dir.join("plain");
+    fs::create_dir_all(&output).unwrap();
+    let summary = extract(&archive(&source), &output, &["d/b.txt"], OnConflict::Abort).unwrap();
+    assert!(!output.join("d/b.txt").exists());
+    assert_eq!((summary.created, summary.skipped), (1, 1));
+    assert_eq!(summary.events, [ExtractEvent::MissingLinkTarget { path: "d/b.txt".to_string(), target: "d/a.txt".to_string() }]);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn selected_file_in_an_archive_with_a_window() {
+    let dir = scratch("window");
+    let source = dir.join("d");
+    fs::create_dir_all(&source).unwrap();
+    let content = |i: usize| (0..100).map(|line| format!("file {} line {}\n", i % 7, line)).collect::<String>();
+    for i in 0..40 {
+        fs::write(source.join(format!("f{:02}.txt", i)), content(i)).unwrap();
+    }
+    // Small frames that refer to the ones before them, the files are spread over many restart intervals.
+    let options = Options::new().lz_bits(10).lz_window(12);
+    let (archive, _) = options.write_archive_seekable(source.to_str().unwrap(), Cursor::new(Vec::new())).unwrap();
+    let archive = ArchiveReader::new(Cursor::new(archive.into_inner())).unwrap().indexed().unwrap().unwrap();
+    assert!(archive.index().frames.len() > 3 * tmy::stream::WINDOW_RESTART);
+
+    let selection = Selection::new(&["d/f37.txt".to_string(), "d/f03.txt".to_string()]);
+    let mut output = Vec::new();
+    Archive::cat_stream(archive.select_contents(&selection), &selection, &mut output).unwrap();
+    assert_eq!(output, [content(3), content(37)].concat().into_bytes());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+/// An archive of exactly `entries`, written without the checks of `Archive::stream_from_disk`.
+/// Every file contains its own path.
+fn crafted(entries: &[Entry]) -> Vec<u8> {
+    let mut encoder = Options::new().encoder(Vec::new()).unwrap();
+    let mut writer = EntryWriter::new(&mut encoder);
+    for entry in entries {
+        writer.write_entry(entry.clone()).unwrap();
+        if let Entry::File { path, .. } = entry {
+            writer.write_content(path.as_bytes()).unwrap();
+            writer.write_content(&crc32c::crc32c(path.as_bytes()).to_le_bytes()).unwrap();
+        }
+    }
+    writer.finish().unwrap();
+    encoder.finish().unwrap()
+}
+
+fn directory(path: &str) -> Entry {
+    Entry::Directory { path: path.to_string(), metadata: Metadata { mode: 0o755, ..Default::default() } }
+}
+
+fn file(path: &str) -> Entry {
+    Entry::File { path: path.to_string(), metadata: Metadata { mode: 0o644, ..Default::default() }, size: path.len() as u64 }
+}
+
+#[test]
+fn unsafe_paths_are_refused() {
+    let dir = scratch("unsafe-paths");
+    let absolute = dir.join("absolute.txt").to_str().unwrap().to_string();
+    fs::write(dir.join("outside.txt"), b"outside").unwrap();
+    let symlink = Entry::Symlink { path: "d/link".to_string(), metadata: Metadata::default(), target: "inner".to_string() };
+    let hardlink = Entry::Hardlink { path: "d/link".to_string(), target: "../outside.txt".to_string() };
+    // The archives and where their last entry ends up if unsafe paths are allowed.
+    let cases = [
+        (vec![directory("d"), file("../escaped.txt")], dir.join("escaped.txt")),
+        (vec![directory("d"), file("d/../../dotted.txt")], dir.join("dotted.txt")),
+        (vec![directory("d"), file(&absolute)], dir.join("absolute.txt")),
+        (vec![directory("d"), directory("d/inner"), symlink, file("d/link/below.txt")], dir.join("out/d/inner/below.txt")),
+        (vec![directory("d"), hardlink], dir.join("out/d/link")),
+    ];
+    for (entries, target) in cases {
+        let archive = crafted(&entries);
+        let name = entries.last().unwrap().path();
+        let output = dir.join("out");
+        let _ = fs::remove_dir_all(&output);
+        fs::create_dir_all(&output).unwrap();
+        let result = extract(&archive, &output, &[], OnConflict::Abort);
+        assert!(matches!(result, Err(Error::UnsafePath { .. })), "{} extracted: {:?}", name, result);
+        assert!(!target.exists(), "{} created", name);
+
+        fs::remove_dir_all(&output).unwrap();
+        fs::create_dir_all(&output).unwrap();
+        let options = ExtractOptions { on_conflict: OnConflict::Abort, allow_unsafe: true };
+        ArchiveReader::new(&archive[..]).unwrap().extract(output.to_str().unwrap(), &Selection::new(&[]), options).unwrap();
+        assert!(target.exists(), "{} not extracted with unsafe paths allowed", name);
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn special_files_are_skipped() {
+    let dir = scratch("special-files");
+    let source = dir.join("d");
+    fs::create_dir_all(&source).unwrap();
+    fs::write(source.join("file.txt"), b"content").unwrap();
+    let fifo = source.join("fifo");
+    assert!(std::process::Command::new("mkfifo").arg(&fifo).status().unwrap().success());
+
+    let (archive, summary) = Options::new().write_archive(source.to_str().unwrap(), Vec::new()).unwrap();
+    assert_eq!(summary.skipped, vec![fifo.to_str().unwrap().to_string()]);
+    let output = dir.join("out");
+    fs::create_dir_all(&output).unwrap();
+    extract(&archive, &output, &[], OnConflict::Skip).unwrap();
+    assert_eq!(fs::read(output.join("d/file.txt")).unwrap(), b"content");
+    assert!(fs::symlink_metadata(output.join("d/fifo")).is_err());
+
+    // A special file on its own is an error, there would be nothing to archive.
+    assert!(matches!(Options::new().write_archive(fifo.to_str().unwrap(), Vec::new()), Err(Error::Io { .. })));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn patterns_with_many_wildcards_match_quickly() {
+    let path = "a".repeat(60) + "/" + &"a".repeat(60);
+    let start = std::time::Instant::now();
+    for (pattern, selected) in [("*a*a*a*a*a*a*a*a*a*b", false), ("**a**a**a**a**a**a**a**b", false), ("*a*a*a*/**a*a*a*a", true)] {
+        assert_eq!(Selection::new(&[pattern.to_string()]).selects(&path), selected, "{}", pattern);
+    }
+    assert!(start.elapsed() < std::time::Duration::from_secs(1));
+}
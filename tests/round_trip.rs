@@ -0,0 +1,93 @@
+use tmy::{Codec, Error, Options, Parser, MAX_LEVEL};
+
+/// Deterministic pseudo random bytes, a linear congruential generator.
+fn random(len: usize) -> Vec<u8> {
+    std::iter::successors(Some(5u64), |x| Some(x.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407)))
+        .map(|x| (x >> 33) as u8)
+        .take(len)
+        .collect()
+}
+
+/// Empty, constant, text and random input.
+fn inputs() -> Vec<(&'static str, Vec<u8>)> {
+    vec![
+        ("empty", Vec::new()),
+        ("constant", vec![b'a'; 300_000]),
+        ("text", [&include_bytes!("../src/lz_77.rs")[..], include_bytes!("../src/archive.rs")].concat().repeat(2)),
+        ("random", random(200_000)),
+    ]
+}
+
+fn assert_round_trip(options: &Options, name: &str, input: &[u8], description: &str) {
+    let encoded = options.encode(input).unwrap_or_else(|e| panic!("encoding {} with {}: {}", name, description, e));
+    let decoded = tmy::decode(&encoded, None).unwrap_or_else(|e| panic!("decoding {} with {}: {}", name, description, e));
+    assert!(decoded == input, "{} changed with {}", name, description);
+}
+
+#[test]
+fn levels() {
+    for (name, input) in inputs() {
+        for level in 0..=MAX_LEVEL {
+            assert_round_trip(&Options::new().level(level), name, &input, &format!("level {}", level));
+            if (2..MAX_LEVEL).contains(&level) {
+                assert_round_trip(&Options::new().level(level).fse(), name, &input, &format!("level {} with FSE", level));
+            }
+        }
+    }
+}
+
+#[test]
+fn level_9_is_no_larger_than_level_8() {
+    for (name, input) in inputs().into_iter().filter(|(name, _)| ["constant", "text"].contains(name)) {
+        let (level_8, level_9) = (Options::new().level(8).encode(&input).unwrap(), Options::new().level(9).encode(&input).unwrap());
+        assert!(level_9.len() <= level_8.len(), "{}: level 9 {} bytes, level 8 {}", name, level_9.len(), level_8.len());
+    }
+}
+
+#[test]
+fn fse_needs_a_huffman_stage() {
+    // Store only, LZ77 without entropy coding and the range coder of level 9.
+    for level in [0, 1, MAX_LEVEL] {
+        assert!(matches!(Options::new().level(level).fse().validate(), Err(Error::InvalidOptions(_))), "level {} accepted", level);
+    }
+    for pipeline in [vec![Codec::Store], vec![Codec::Lz77], vec![Codec::Lz77Range], vec![Codec::Bwt]] {
+        assert!(matches!(Options::new().pipeline(pipeline.clone()).fse().encode(b"data"), Err(Error::InvalidOptions(_))), "{:?} accepted", pipeline);
+    }
+}
+
+#[test]
+fn codecs() {
+    let pipelines = [
+        vec![Codec::Store],
+        vec![Codec::Lz77],
+        vec![Codec::Huffman],
+        vec![Codec::Lz77, Codec::Huffman],
+        vec![Codec::Lz77Huffman],
+        vec![Codec::Lz77Range],
+        vec![Codec::Fse],
+        vec![Codec::Lz77, Codec::Fse],
+        vec![Codec::Bwt],
+    ];
+    for (name, input) in inputs() {
+        for pipeline in &pipelines {
+            // Small chunks give several frames, the window lets them refer to each other.
+            for window in [0, 14] {
+                let options = Options::new().pipeline(pipeline.clone()).lz_bits(16).huffman_bits(14).lz_window(window);
+                assert_round_trip(&options, name, &input, &format!("{:?} and window {}", pipeline, window));
+            }
+        }
+    }
+}
+
+#[test]
+fn parsers() {
+    for (name, input) in inputs() {
+        for parser in [Parser::Fast, Parser::Greedy, Parser::Lazy(1), Parser::Lazy(2), Parser::Optimal] {
+            for pipeline in [vec![Codec::Lz77], vec![Codec::Lz77Huffman], vec![Codec::Lz77Range]] {
+                let options = Options::new().pipeline(pipeline.clone()).parser(parser).lz_bits(16);
+                assert_round_trip(&options, name, &input, &format!("{:?} and {:?}", pipeline, parser));
+            }
+        }
+    }
+}
+